@@ -0,0 +1,239 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use num_rational::Rational64;
+use num_traits::{One, Zero};
+use tracing::error;
+
+use crate::{adherence, catch_up_consumption, journal, load_data, organizer, store_data, travel, DATA_LOCK};
+use crate::journal::{DoseRecord, JournalAction, JournalEvent, StockChange};
+use crate::model::{Drug, Patient, QuantityUnit, StockBatch};
+
+
+/// The maximum number of days that can be taken at once.
+pub(crate) const MAX_TAKE_DAYS: i64 = 3 * 366;
+
+
+/// A modification of the stock along with the journal entry describing it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Modification {
+    action: JournalAction,
+    /// For undo operations, the number of the reverted operation.
+    undoes: Option<u64>,
+    changes: Vec<StockChange>,
+}
+
+/// Why a modification has been rejected or could not be performed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ModifyError {
+    /// The request is invalid; the message is meant for the client.
+    Invalid(String),
+    /// The drug addressed by the request does not exist.
+    NotFound(String),
+    /// The data or journal could not be loaded or stored; the details have been logged.
+    Failed(&'static str),
+}
+
+/// Stock to be added to or removed from a drug, as entered by the user.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Replenishment {
+    pub quantity: Rational64,
+    pub unit: QuantityUnit,
+    /// Whether the drug has been obtained with a refill of its prescription.
+    pub from_prescription: bool,
+    pub expiry: Option<NaiveDate>,
+    pub lot: Option<String>,
+}
+
+
+impl Modification {
+    pub fn new(action: JournalAction, changes: Vec<StockChange>) -> Self {
+        Self {
+            action,
+            undoes: None,
+            changes,
+        }
+    }
+
+    pub fn undo(undone_operation: u64, changes: Vec<StockChange>) -> Self {
+        Self {
+            action: JournalAction::Undo,
+            undoes: Some(undone_operation),
+            changes,
+        }
+    }
+
+    pub fn undoes(&self) -> Option<u64> { self.undoes }
+}
+
+impl ModifyError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Invalid(message) => message,
+            Self::NotFound(message) => message,
+            Self::Failed(message) => message,
+        }
+    }
+}
+
+impl From<&str> for ModifyError {
+    fn from(message: &str) -> Self {
+        Self::Invalid(message.to_owned())
+    }
+}
+
+impl From<String> for ModifyError {
+    fn from(message: String) -> Self {
+        Self::Invalid(message)
+    }
+}
+
+
+/// Loads the data of `patient`, applies `modify` to it, stores it and records the changes in the
+/// journal, all while holding the data lock.
+///
+/// `modify` is passed the data after the automatic consumption up to now has been deducted, the
+/// journal and the current time. Nothing is stored if it fails. Returns the modified data along
+/// with the modification.
+pub(crate) async fn modify_data<F>(patient: &Patient, token_label: Option<&str>, modify: F) -> Result<(Vec<Drug>, Modification), ModifyError>
+    where F: FnOnce(&mut Vec<Drug>, &[JournalEvent], DateTime<Utc>) -> Result<Modification, ModifyError>
+{
+    let _data_guard = DATA_LOCK.lock().await;
+    let loaded_data = load_data(patient).await
+        .ok_or(ModifyError::Failed("failed to load data"))?;
+    let now = Utc::now();
+    let mut caught_up_data = loaded_data.clone();
+    let consumption_changes = catch_up_consumption(&mut caught_up_data, now);
+    let events = journal::load_journal(patient).await
+        .ok_or(ModifyError::Failed("failed to load journal"))?;
+
+    let mut data = caught_up_data.clone();
+    let modification = modify(&mut data, &events, now)?;
+
+    if !store_data(patient, &data).await {
+        return Err(ModifyError::Failed("failed to store data"));
+    }
+    // changes must not go unrecorded; put back the data matching the journal if it cannot be written
+    if !journal::record(patient, JournalAction::AutoConsume, None, &consumption_changes).await {
+        roll_back(patient, &loaded_data).await;
+        return Err(ModifyError::Failed("failed to record change in journal"));
+    }
+    let recorded = match modification.undoes {
+        Some(op) => journal::record_undo(patient, op, token_label, &modification.changes).await,
+        None => journal::record(patient, modification.action, token_label, &modification.changes).await,
+    };
+    if !recorded {
+        roll_back(patient, &caught_up_data).await;
+        return Err(ModifyError::Failed("failed to record change in journal"));
+    }
+    Ok((data, modification))
+}
+
+async fn roll_back(patient: &Patient, data: &[Drug]) {
+    if !store_data(patient, data).await {
+        error!("failed to roll back the data of {:?}; it contains changes missing from the journal", patient.name);
+    }
+}
+
+/// Adds stock to or, with a negative quantity, removes stock from `drug`.
+pub(crate) fn replenish(drug: &mut Drug, replenishment: Replenishment, today: NaiveDate) -> Result<Modification, ModifyError> {
+    let amount = drug.to_units(replenishment.quantity, replenishment.unit);
+    if amount.is_zero() {
+        return Err("\"amount\" must not be 0".into());
+    }
+    let lot = replenishment.lot
+        .as_deref()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_owned());
+    if amount < Zero::zero() && (replenishment.expiry.is_some() || lot.is_some()) {
+        return Err("expiry dates and lots can only be given when adding stock".into());
+    }
+
+    let action = if replenishment.from_prescription {
+        if amount < Zero::zero() {
+            return Err("cannot reduce the stock from a prescription".into());
+        }
+        drug.use_prescription_refill(today)?;
+        JournalAction::ReplenishFromPrescription
+    } else {
+        JournalAction::for_adjustment(&amount)
+    };
+    let delta = if amount > Zero::zero() {
+        drug.add_batch(StockBatch::new(amount, replenishment.expiry, lot))
+    } else {
+        drug.adjust(&amount)
+    };
+    Ok(Modification::new(action, vec![StockChange::new(drug.id(), delta, drug.remaining())]))
+}
+
+/// Logs a dose of a drug taken as needed; a single unit if `amount` is not given.
+pub(crate) fn take_dose(drug: &mut Drug, amount: Option<Rational64>) -> Result<Modification, ModifyError> {
+    if !drug.as_needed() {
+        return Err("doses can only be logged for drugs taken as needed".into());
+    }
+    let amount = amount.unwrap_or_else(One::one);
+    if amount <= Zero::zero() {
+        return Err("\"amount\" must be positive".into());
+    }
+    let delta = drug.adjust(&-amount);
+    Ok(Modification::new(JournalAction::TakeDose, vec![StockChange::new(drug.id(), delta, drug.remaining())]))
+}
+
+/// Logs the outcome of a scheduled dose (see [`adherence::log_dose`]).
+pub(crate) fn log_dose(drug: &mut Drug, events: &[JournalEvent], record: DoseRecord, today: NaiveDate) -> Result<Modification, ModifyError> {
+    let change = adherence::log_dose(drug, events, record, today)?;
+    Ok(Modification::new(JournalAction::LogDose, vec![change]))
+}
+
+/// Moves stock of `drug` between storage locations.
+pub(crate) fn transfer(drug: &mut Drug, amount: Rational64, from: &str, to: &str) -> Result<Modification, ModifyError> {
+    drug.transfer(&amount, from.trim(), to.trim())?;
+    Ok(Modification::new(JournalAction::Transfer, vec![StockChange::new(drug.id(), Zero::zero(), drug.remaining())]))
+}
+
+/// Reduces every drug by the given number of days' dosage.
+pub(crate) fn take_days(data: &mut [Drug], days: i64, today: NaiveDate) -> Result<Modification, ModifyError> {
+    if days <= 0 || days > MAX_TAKE_DAYS {
+        return Err(format!("\"days\" must be between 1 and {}", MAX_TAKE_DAYS).into());
+    }
+    let mut changes = Vec::new();
+    for drug in data {
+        let delta = drug.take_days(days, today)?;
+        if !delta.is_zero() {
+            changes.push(StockChange::new(drug.id(), delta, drug.remaining()));
+        }
+    }
+    Ok(Modification::new(JournalAction::TakeDays, changes))
+}
+
+/// Removes all batches that have expired by `today`.
+pub(crate) fn discard_expired(data: &mut [Drug], today: NaiveDate) -> Modification {
+    let mut changes = Vec::new();
+    for drug in data {
+        let delta = drug.discard_expired(today);
+        if !delta.is_zero() {
+            changes.push(StockChange::new(drug.id(), delta, drug.remaining()));
+        }
+    }
+    Modification::new(JournalAction::DiscardExpired, changes)
+}
+
+/// Deducts the pill organizer contents of the week beginning with `start`.
+pub(crate) fn fill_organizer(data: &mut [Drug], start: NaiveDate) -> Modification {
+    Modification::new(JournalAction::FillOrganizer, organizer::fill_organizer(data, start))
+}
+
+/// Moves the amounts to pack for `trip` to `location`, or to the default travel location if it is
+/// not given or empty.
+pub(crate) fn pack_trip(data: &mut [Drug], today: NaiveDate, trip: &travel::Trip, location: Option<&str>) -> Modification {
+    let location = location
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .unwrap_or(travel::DEFAULT_TRAVEL_LOCATION);
+    Modification::new(JournalAction::Transfer, travel::pack_trip(data, today, trip, location))
+}
+
+/// Reverts the given operation or, if `None`, the most recent one (see [`journal::undo`]).
+pub(crate) fn undo(data: &mut [Drug], events: &[JournalEvent], operation: Option<u64>) -> Result<Modification, ModifyError> {
+    let (undone_operation, changes) = journal::undo(data, events, operation)?;
+    Ok(Modification::undo(undone_operation, changes))
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Days, Duration, Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use num_rational::Rational64;
+use num_traits::Zero;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{catch_up_consumption, filters, journal, load_data, respond_400, respond_500, Auth};
+use crate::journal::{DoseRecord, DoseStatus, JournalEvent, StockChange};
+use crate::model::Drug;
+use crate::schedule::DoseSlot;
+
+
+/// The periods, in days, for which adherence reports are offered.
+pub(crate) const REPORT_PERIODS: [i64; 3] = [7, 30, 90];
+
+/// The period, in days, of the adherence report if not specified otherwise.
+pub(crate) const DEFAULT_REPORT_DAYS: i64 = 30;
+
+/// The maximum period, in days, of an adherence report.
+pub(crate) const MAX_REPORT_DAYS: i64 = 366;
+
+
+/// A dose scheduled on a specific day and time of day.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct ScheduledDose {
+    drug_id: u64,
+    trade_name: String,
+    date: NaiveDate,
+    slot: DoseSlot,
+    amount: Rational64,
+    /// How the dose has been logged, if at all.
+    status: Option<DoseStatus>,
+}
+
+/// The number of scheduled doses by outcome.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub(crate) struct AdherenceSummary {
+    scheduled: u64,
+    taken: u64,
+    late: u64,
+    skipped: u64,
+    /// Doses of past days that have not been logged; counted as missed.
+    not_logged: u64,
+    /// Doses of today that have not been logged yet; not counted at all.
+    pending: u64,
+    /// The percentage of doses taken (on time or late) among all counted doses.
+    percentage: Option<Rational64>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct DrugAdherence {
+    drug_id: u64,
+    trade_name: String,
+    summary: AdherenceSummary,
+}
+
+/// How well the dosage schedule has been adhered to over a period of days.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct AdherenceReport {
+    days: i64,
+    first_date: NaiveDate,
+    last_date: NaiveDate,
+    overall: AdherenceSummary,
+    drugs: Vec<DrugAdherence>,
+    /// Doses that have been skipped or not logged, most recent first.
+    missed: Vec<ScheduledDose>,
+}
+
+#[derive(Template)]
+#[template(path = "adherence.html", escape = "none")]
+struct AdherenceTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub date: NaiveDate,
+    pub doses: Vec<ScheduledDose>,
+    pub periods: [i64; 3],
+    pub report: AdherenceReport,
+}
+
+
+impl ScheduledDose {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn date(&self) -> NaiveDate { self.date }
+    pub fn slot(&self) -> DoseSlot { self.slot }
+    pub fn amount(&self) -> Rational64 { self.amount }
+
+    pub fn status_str(&self) -> &'static str {
+        self.status
+            .map(|s| s.as_str())
+            .unwrap_or("")
+    }
+}
+
+impl AdherenceSummary {
+    pub fn scheduled(&self) -> u64 { self.scheduled }
+    pub fn taken(&self) -> u64 { self.taken }
+    pub fn late(&self) -> u64 { self.late }
+    pub fn skipped(&self) -> u64 { self.skipped }
+    pub fn not_logged(&self) -> u64 { self.not_logged }
+    pub fn percentage(&self) -> Option<Rational64> { self.percentage }
+
+    fn count(&mut self, status: Option<DoseStatus>, pending: bool) {
+        self.scheduled += 1;
+        match status {
+            Some(DoseStatus::Taken) => self.taken += 1,
+            Some(DoseStatus::Late) => self.late += 1,
+            Some(DoseStatus::Skipped) => self.skipped += 1,
+            None if pending => self.pending += 1,
+            None => self.not_logged += 1,
+        }
+    }
+
+    fn add(&mut self, other: &AdherenceSummary) {
+        self.scheduled += other.scheduled;
+        self.taken += other.taken;
+        self.late += other.late;
+        self.skipped += other.skipped;
+        self.not_logged += other.not_logged;
+        self.pending += other.pending;
+    }
+
+    fn finish(&mut self) {
+        let counted = self.scheduled - self.pending;
+        self.percentage = if counted == 0 {
+            None
+        } else {
+            let adhered = i64::try_from(self.taken + self.late).unwrap();
+            Some(Rational64::new(100 * adhered, i64::try_from(counted).unwrap()))
+        };
+    }
+}
+
+impl DrugAdherence {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn summary(&self) -> &AdherenceSummary { &self.summary }
+}
+
+impl AdherenceReport {
+    pub fn days(&self) -> i64 { self.days }
+    pub fn first_date(&self) -> NaiveDate { self.first_date }
+    pub fn overall(&self) -> &AdherenceSummary { &self.overall }
+    pub fn drugs(&self) -> &[DrugAdherence] { &self.drugs }
+    pub fn missed(&self) -> &[ScheduledDose] { &self.missed }
+}
+
+
+fn scheduled_doses_of(
+    drug: &Drug,
+    date: NaiveDate,
+    logged: &HashMap<(u64, NaiveDate, DoseSlot), DoseStatus>,
+) -> Vec<ScheduledDose> {
+    let dosage = drug.dosage_on(date);
+    DoseSlot::ALL.into_iter()
+        .filter(|slot| dosage.in_slot(*slot) > Zero::zero())
+        .map(|slot| ScheduledDose {
+            drug_id: drug.id(),
+            trade_name: drug.trade_name().to_owned(),
+            date,
+            slot,
+            amount: dosage.in_slot(slot),
+            status: logged.get(&(drug.id(), date, slot)).copied(),
+        })
+        .collect()
+}
+
+/// Lists the doses of all shown drugs scheduled on `date` along with how they have been logged.
+pub(crate) fn scheduled_doses(data: &[Drug], events: &[JournalEvent], date: NaiveDate) -> Vec<ScheduledDose> {
+    let logged = journal::logged_doses(events);
+    data.iter()
+        .filter(|drug| drug.show())
+        .flat_map(|drug| scheduled_doses_of(drug, date, &logged))
+        .collect()
+}
+
+/// Logs the outcome of the dose of `drug` scheduled as given in `record`.
+///
+/// Taking the dose reduces the stock by the scheduled amount, unless the consumption of the drug is
+/// deducted automatically; changing the outcome of a dose logged earlier corrects the stock
+/// accordingly. Returns the change to record in the journal.
+pub(crate) fn log_dose(
+    drug: &mut Drug,
+    events: &[JournalEvent],
+    record: DoseRecord,
+    today: NaiveDate,
+) -> Result<StockChange, &'static str> {
+    if record.date > today {
+        return Err("doses cannot be logged in advance");
+    }
+    let amount = drug.dosage_on(record.date).in_slot(record.slot);
+    if amount.is_zero() {
+        return Err("no dose of this drug is scheduled at that time");
+    }
+
+    let consumed_before = journal::logged_doses(events)
+        .get(&(drug.id(), record.date, record.slot))
+        .map(|status| status.consumes())
+        .unwrap_or(false);
+    let delta = if drug.tracks_consumption() {
+        Zero::zero()
+    } else {
+        match (consumed_before, record.status.consumes()) {
+            (false, true) => drug.adjust(&-amount),
+            (true, false) => drug.adjust(&amount),
+            _ => Zero::zero(),
+        }
+    };
+    Ok(StockChange::new(drug.id(), delta, drug.remaining()).with_dose(record))
+}
+
+/// Reports the adherence to the shown drugs over the `days` days ending with `today`.
+///
+/// Doses are only counted from the first day for which any dose of the drug has been logged, so
+/// that drugs are not reported as missed before their doses have started being logged.
+pub(crate) fn adherence_report(data: &[Drug], events: &[JournalEvent], today: NaiveDate, days: i64) -> AdherenceReport {
+    let logged = journal::logged_doses(events);
+    let first_date = u64::try_from(days - 1).ok()
+        .and_then(|d| today.checked_sub_days(Days::new(d)))
+        .unwrap_or(NaiveDate::MIN);
+
+    let mut overall = AdherenceSummary::default();
+    let mut drugs = Vec::new();
+    let mut missed = Vec::new();
+    for drug in data.iter().filter(|drug| drug.show()) {
+        let tracking_start = logged.keys()
+            .filter(|(drug_id, _, _)| *drug_id == drug.id())
+            .map(|(_, date, _)| *date)
+            .min();
+        let mut date = match tracking_start {
+            Some(ts) => ts.max(first_date),
+            None => continue,
+        };
+
+        let mut summary = AdherenceSummary::default();
+        while date <= today {
+            for dose in scheduled_doses_of(drug, date, &logged) {
+                summary.count(dose.status, date == today);
+                let is_missed = match dose.status {
+                    Some(status) => !status.consumes(),
+                    None => date < today,
+                };
+                if is_missed {
+                    missed.push(dose);
+                }
+            }
+            date += Duration::days(1);
+        }
+        if summary.scheduled == 0 {
+            continue;
+        }
+        overall.add(&summary);
+        summary.finish();
+        drugs.push(DrugAdherence {
+            drug_id: drug.id(),
+            trade_name: drug.trade_name().to_owned(),
+            summary,
+        });
+    }
+    overall.finish();
+    missed.sort_by_key(|dose| Reverse((dose.date, dose.slot)));
+
+    AdherenceReport {
+        days,
+        first_date,
+        last_date: today,
+        overall,
+        drugs,
+        missed,
+    }
+}
+
+/// Parses the number of days of an adherence report.
+pub(crate) fn parse_report_days(days: Option<&str>) -> Result<i64, String> {
+    match days {
+        None => Ok(DEFAULT_REPORT_DAYS),
+        Some(days_str) => match days_str.parse() {
+            Ok(d) if d > 0 && d <= MAX_REPORT_DAYS => Ok(d),
+            _ => Err(format!("\"days\" must be between 1 and {}", MAX_REPORT_DAYS)),
+        },
+    }
+}
+
+
+pub(crate) async fn handle_get_adherence(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+    let days = match parse_report_days(query_values.get("days").map(|d| d.as_str())) {
+        Ok(d) => d,
+        Err(msg) => return respond_400(&msg),
+    };
+
+    let now = Utc::now();
+    let today = now.with_timezone(&Local).date_naive();
+    let date: NaiveDate = match query_values.get("date") {
+        None => today,
+        Some(date_str) => match date_str.parse() {
+            Ok(d) => d,
+            Err(_) => return respond_400("invalid value for \"date\""),
+        },
+    };
+
+    let mut data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    catch_up_consumption(&mut data, now);
+    let events = match journal::load_journal(&auth.patient).await {
+        None => return respond_500(),
+        Some(e) => e,
+    };
+
+    let template = AdherenceTemplate {
+        token,
+        patient: &auth.patient.name,
+        date,
+        doses: scheduled_doses(&data, &events, date),
+        periods: REPORT_PERIODS,
+        report: adherence_report(&data, &events, today, days),
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_traits::One;
+    use crate::schedule::{DailyDosage, DosagePhase};
+    use super::*;
+
+    fn date(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 5, d).unwrap()
+    }
+
+    fn drug() -> Drug {
+        Drug::for_test(1, 10)
+            .with_dosage(One::one(), Zero::zero(), Rational64::new(1, 2), Zero::zero())
+    }
+
+    fn dose_event(operation: u64, d: u32, slot: &str, status: &str) -> JournalEvent {
+        let json = format!(
+            "{{\"operation\":{},\"timestamp\":\"2023-05-0{}T20:00:00Z\",\"drug_id\":1,\"action\":\"log-dose\",\"delta\":[0,1],\"remaining\":[0,1],\"token_label\":null,\"dose\":{{\"date\":\"2023-05-0{}\",\"slot\":{:?},\"status\":{:?}}}}}",
+            operation, d, d, slot, status,
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_log_dose() {
+        let mut drug = drug();
+        let taken = DoseRecord { date: date(2), slot: DoseSlot::Evening, status: DoseStatus::Taken };
+        let change = log_dose(&mut drug, &[], taken, date(2)).unwrap();
+        assert_eq!(change.delta, Rational64::new(-1, 2));
+        assert_eq!(drug.remaining(), Rational64::new(19, 2));
+
+        // correcting the dose to skipped gives the amount back; logging it as late takes nothing more
+        let events = vec![dose_event(1, 2, "evening", "taken")];
+        let skipped = DoseRecord { status: DoseStatus::Skipped, ..taken };
+        assert_eq!(log_dose(&mut drug, &events, skipped, date(2)).unwrap().delta, Rational64::new(1, 2));
+        let late = DoseRecord { status: DoseStatus::Late, ..taken };
+        assert!(log_dose(&mut drug, &events, late, date(2)).unwrap().delta.is_zero());
+
+        let noon = DoseRecord { slot: DoseSlot::Noon, ..taken };
+        assert!(log_dose(&mut drug, &events, noon, date(2)).is_err());
+        assert!(log_dose(&mut drug, &events, taken, date(1)).is_err());
+    }
+
+    #[test]
+    fn test_adherence_report() {
+        let events = vec![
+            dose_event(1, 2, "morning", "taken"),
+            dose_event(2, 2, "evening", "skipped"),
+            dose_event(3, 3, "morning", "late"),
+        ];
+        let report = adherence_report(&[drug()], &events, date(4), 7);
+        let summary = report.overall();
+        // nothing is counted before May 2; the doses of May 4 are still pending
+        assert_eq!(summary.scheduled(), 6);
+        assert_eq!((summary.taken(), summary.late(), summary.skipped(), summary.not_logged()), (1, 1, 1, 1));
+        assert_eq!(summary.percentage(), Some(Rational64::from_integer(50)));
+
+        let missed: Vec<(NaiveDate, DoseSlot)> = report.missed().iter()
+            .map(|dose| (dose.date(), dose.slot()))
+            .collect();
+        assert_eq!(missed, vec![(date(3), DoseSlot::Evening), (date(2), DoseSlot::Evening)]);
+    }
+
+    #[test]
+    fn test_adherence_report_finished() {
+        let dosage = DailyDosage {
+            evening: Rational64::new(1, 2),
+            ..DailyDosage::zero()
+        };
+        let mut drug = drug();
+        drug.set_dosage_plan(vec![DosagePhase { start: date(1), end: date(2), dosage }], true);
+        assert!(drug.hide_if_finished(date(4)));
+
+        // the evening dose of May 2 has not been logged, but the drug is no longer shown
+        let events = vec![dose_event(1, 2, "morning", "taken")];
+        let report = adherence_report(&[drug], &events, date(4), 7);
+        assert_eq!(report.overall().scheduled(), 0);
+        assert!(report.missed().is_empty());
+    }
+
+    #[test]
+    fn test_parse_report_days() {
+        assert_eq!(parse_report_days(None), Ok(DEFAULT_REPORT_DAYS));
+        assert_eq!(parse_report_days(Some("90")), Ok(90));
+        assert!(parse_report_days(Some("0")).is_err());
+        assert!(parse_report_days(Some("-7")).is_err());
+        assert!(parse_report_days(Some("100000000")).is_err());
+        assert!(parse_report_days(Some("month")).is_err());
+
+        // unchecked periods reaching past the first date still work
+        let events = vec![dose_event(1, 2, "morning", "taken")];
+        assert_eq!(adherence_report(&[drug()], &events, date(4), i64::MAX).overall().taken(), 1);
+    }
+}
@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use chrono::{Local, NaiveDate, Utc};
+use hyper::{Body, Method, Request, Response};
+use num_rational::Rational64;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tracing::error;
+
+use crate::{
+    actions, adherence, catch_up_consumption, drugs_to_show, ingredients, interactions, journal, load_data,
+    load_drugs_to_display, min_weeks_per_prescription, organizer, planner, travel, Auth,
+};
+use crate::actions::{modify_data, Modification, ModifyError, Replenishment};
+use crate::journal::{DoseRecord, DoseStatus, JournalEvent};
+use crate::model::{find_drug_by_id, Drug, DrugToDisplay, LocationStock, Patient, QuantityUnit, ReplenishmentStatus};
+use crate::schedule::DoseSlot;
+use crate::util::{parse_amount, ParseDecimalError};
+
+
+pub(crate) const API_PREFIX: &str = "/api/v1/";
+
+
+#[derive(Clone, Debug, Serialize)]
+struct ApiError<'a> {
+    error: ApiErrorBody<'a>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApiErrorBody<'a> {
+    status: u16,
+    message: &'a str,
+}
+
+/// A failure that is reported to the API client as an error response.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct ApiFailure {
+    status: u16,
+    message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApiDrug {
+    index: usize,
+    #[serde(flatten)] drug: Drug,
+    remaining_days: Option<i64>,
+    remaining_weeks: Option<i64>,
+    run_out_date: Option<NaiveDate>,
+    refill_by_date: Option<NaiveDate>,
+    weeks_per_prescription: Option<i64>,
+    replenishment_status: ReplenishmentStatus,
+    needs_doctor_visit: bool,
+    prescription_warnings: Vec<String>,
+    expiry_warnings: Vec<String>,
+    stock_by_location: Vec<LocationStock>,
+    #[serde(skip_serializing_if = "Option::is_none")] average_dose_day: Option<Rational64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApiDrugList {
+    drugs: Vec<ApiDrug>,
+    min_weeks_per_prescription: Option<i64>,
+}
+
+/// An amount as accepted by the API: a decimal string, an integer or a `[numerator, denominator]`
+/// pair as in the data file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum ApiAmount {
+    Text(String),
+    Integer(i64),
+    Ratio(Rational64),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ReplenishRequest {
+    amount: ApiAmount,
+    /// The unit of `amount`; single units if not given.
+    #[serde(default)] unit: QuantityUnit,
+    /// Whether the drug has been obtained with a refill of its prescription.
+    #[serde(default)] from_prescription: bool,
+    #[serde(default)] expiry: Option<NaiveDate>,
+    #[serde(default)] lot: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct TakeDoseRequest {
+    #[serde(default)] amount: Option<ApiAmount>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct LogDoseRequest {
+    slot: DoseSlot,
+    status: DoseStatus,
+    /// The day on which the dose was scheduled; today if not given.
+    #[serde(default)] date: Option<NaiveDate>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct TransferRequest {
+    amount: ApiAmount,
+    from: String,
+    to: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PackTripRequest {
+    start: NaiveDate,
+    end: NaiveDate,
+    #[serde(default)] buffer_days: i64,
+    #[serde(default)] location: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct FillOrganizerRequest {
+    #[serde(default)] start: Option<NaiveDate>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct TakeDaysRequest {
+    days: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct UndoRequest {
+    #[serde(default)] operation: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct UndoResponse {
+    undone_operation: u64,
+    #[serde(flatten)] drug_list: ApiDrugList,
+}
+
+
+impl ApiDrug {
+    fn from_display(dtd: DrugToDisplay, min_weeks_per_prescription: &Option<i64>) -> Self {
+        let replenishment_status = dtd.needs_replenishment(min_weeks_per_prescription);
+        let stock_by_location = dtd.drug.stock_by_location();
+        Self {
+            index: dtd.index,
+            drug: dtd.drug,
+            remaining_days: dtd.remaining_days,
+            remaining_weeks: dtd.remaining_weeks,
+            run_out_date: dtd.run_out_date,
+            refill_by_date: dtd.refill_by_date,
+            weeks_per_prescription: dtd.weeks_per_prescription,
+            replenishment_status,
+            needs_doctor_visit: dtd.needs_doctor_visit,
+            prescription_warnings: dtd.prescription_warnings,
+            expiry_warnings: dtd.expiry_warnings,
+            stock_by_location,
+            average_dose_day: dtd.average_dose_day,
+        }
+    }
+}
+
+impl ApiFailure {
+    fn new<M: Into<String>>(status: u16, message: M) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn respond(&self) -> Result<Response<Body>, Infallible> {
+        respond_error(self.status, &self.message)
+    }
+}
+
+impl From<ModifyError> for ApiFailure {
+    fn from(error: ModifyError) -> Self {
+        let status = match error {
+            ModifyError::Invalid(_) => 400,
+            ModifyError::NotFound(_) => 404,
+            ModifyError::Failed(_) => 500,
+        };
+        Self::new(status, error.message())
+    }
+}
+
+impl ReplenishRequest {
+    fn to_replenishment(&self) -> Result<Replenishment, ApiFailure> {
+        let quantity = self.amount.to_rational()
+            .map_err(|e| ApiFailure::new(400, format!("invalid value for \"amount\": {}", e)))?;
+        Ok(Replenishment {
+            quantity,
+            unit: self.unit,
+            from_prescription: self.from_prescription,
+            expiry: self.expiry,
+            lot: self.lot.clone(),
+        })
+    }
+}
+
+impl ApiAmount {
+    fn to_rational(&self) -> Result<Rational64, ParseDecimalError> {
+        match self {
+            Self::Text(t) => parse_amount(t),
+            Self::Integer(i) => Ok(Rational64::from_integer(*i)),
+            Self::Ratio(r) => Ok(*r),
+        }
+    }
+}
+
+
+pub(crate) fn respond_error(status: u16, message: &str) -> Result<Response<Body>, Infallible> {
+    let error = ApiError {
+        error: ApiErrorBody {
+            status,
+            message,
+        },
+    };
+    let body_str = serde_json::to_string(&error)
+        .expect("failed to serialize error");
+    let resp = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body_str))
+        .expect("failed to build body");
+    Ok(resp)
+}
+
+fn respond_json<T: Serialize>(value: &T) -> Result<Response<Body>, Infallible> {
+    let body_str = match serde_json::to_string(value) {
+        Ok(bs) => bs,
+        Err(e) => {
+            error!("failed to serialize API response: {}", e);
+            return respond_error(500, "failed to serialize response");
+        },
+    };
+    let resp_res = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble API response body: {}", e);
+            respond_error(500, "failed to assemble response")
+        },
+    }
+}
+
+async fn read_json_body<T: DeserializeOwned>(request: Request<Body>) -> Result<T, ApiFailure> {
+    let body_bytes = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(bb) => bb,
+        Err(e) => {
+            error!("failed to read request body: {}", e);
+            return Err(ApiFailure::new(500, "failed to read request body"));
+        },
+    };
+    serde_json::from_slice(&body_bytes)
+        .map_err(|e| ApiFailure::new(400, format!("invalid request body: {}", e)))
+}
+
+/// Lists the drugs shown on the main page.
+async fn api_drug_list(patient: &Patient, data: &[Drug]) -> Result<ApiDrugList, ApiFailure> {
+    let all_drugs = load_drugs_to_display(patient, data, Utc::now()).await
+        .ok_or_else(|| ApiFailure::new(500, "failed to load journal"))?;
+    let min_weeks_per_prescription = min_weeks_per_prescription(&all_drugs);
+    let drugs = drugs_to_show(all_drugs)
+        .into_iter()
+        .map(|dtd| ApiDrug::from_display(dtd, &min_weeks_per_prescription))
+        .collect();
+    Ok(ApiDrugList {
+        drugs,
+        min_weeks_per_prescription,
+    })
+}
+
+/// Returns a single drug, whether it is shown or not.
+async fn api_drug(patient: &Patient, data: &[Drug], index: usize) -> Result<ApiDrug, ApiFailure> {
+    let mut all_drugs = load_drugs_to_display(patient, data, Utc::now()).await
+        .ok_or_else(|| ApiFailure::new(500, "failed to load journal"))?;
+    let min_weeks_per_prescription = min_weeks_per_prescription(&all_drugs);
+    Ok(ApiDrug::from_display(all_drugs.swap_remove(index), &min_weeks_per_prescription))
+}
+
+fn query_values(request: &Request<Body>) -> HashMap<String, String> {
+    match request.uri().query() {
+        Some(query_str) => form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+fn query_value(request: &Request<Body>, key: &str) -> Option<String> {
+    let query_str = request.uri().query()?;
+    form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+fn parse_id(id_str: &str, data: &[Drug]) -> Result<usize, ModifyError> {
+    let id: u64 = id_str.parse()
+        .map_err(|_| "invalid drug ID")?;
+    find_drug_by_id(data, id)
+        .ok_or_else(|| ModifyError::NotFound("drug not found".to_owned()))
+}
+
+/// Responds with the drug addressed by `id_str` after it has been modified.
+async fn respond_modified_drug(patient: &Patient, result: Result<(Vec<Drug>, Modification), ModifyError>, id_str: &str) -> Result<Response<Body>, Infallible> {
+    let (data, _modification) = match result {
+        Ok(dm) => dm,
+        Err(e) => return ApiFailure::from(e).respond(),
+    };
+    let index = match parse_id(id_str, &data) {
+        Ok(i) => i,
+        Err(e) => return ApiFailure::from(e).respond(),
+    };
+    match api_drug(patient, &data, index).await {
+        Ok(drug) => respond_json(&drug),
+        Err(f) => f.respond(),
+    }
+}
+
+/// Responds with the list of drugs after they have been modified.
+async fn respond_modified_list(patient: &Patient, result: Result<(Vec<Drug>, Modification), ModifyError>) -> Result<Response<Body>, Infallible> {
+    let (data, _modification) = match result {
+        Ok(dm) => dm,
+        Err(e) => return ApiFailure::from(e).respond(),
+    };
+    match api_drug_list(patient, &data).await {
+        Ok(list) => respond_json(&list),
+        Err(f) => f.respond(),
+    }
+}
+
+
+pub(crate) async fn handle_api_request(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path()
+        .strip_prefix(API_PREFIX)
+        .unwrap_or("")
+        .trim_end_matches('/')
+        .to_owned();
+    let pieces: Vec<&str> = path.split('/').collect();
+
+    match pieces.as_slice() {
+        ["drugs"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            match api_drug_list(&auth.patient, &data).await {
+                Ok(list) => respond_json(&list),
+                Err(f) => f.respond(),
+            }
+        },
+        ["drugs", id_str] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let index = match parse_id(id_str, &data) {
+                Ok(i) => i,
+                Err(e) => return ApiFailure::from(e).respond(),
+            };
+            match api_drug(&auth.patient, &data, index).await {
+                Ok(drug) => respond_json(&drug),
+                Err(f) => f.respond(),
+            }
+        },
+        ["drugs", id_str, "history"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let index = match parse_id(id_str, &data) {
+                Ok(i) => i,
+                Err(e) => return ApiFailure::from(e).respond(),
+            };
+            let journal = match journal::load_journal(&auth.patient).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(j) => j,
+            };
+            let events: Vec<JournalEvent> = journal.into_iter()
+                .filter(|ev| ev.drug_id() == data[index].id())
+                .collect();
+            respond_json(&events)
+        },
+        ["drugs", id_str, "replenish"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let id_str = id_str.to_string();
+            let replenish_request: ReplenishRequest = match read_json_body(request).await {
+                Ok(rr) => rr,
+                Err(f) => return f.respond(),
+            };
+            let replenishment = match replenish_request.to_replenishment() {
+                Ok(r) => r,
+                Err(f) => return f.respond(),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, now| {
+                let index = parse_id(&id_str, data)?;
+                actions::replenish(&mut data[index], replenishment, now.with_timezone(&Local).date_naive())
+            }).await;
+            respond_modified_drug(&auth.patient, result, &id_str).await
+        },
+        ["drugs", id_str, "take-dose"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let id_str = id_str.to_string();
+            let take_dose_request: TakeDoseRequest = match read_json_body(request).await {
+                Ok(tdr) => tdr,
+                Err(f) => return f.respond(),
+            };
+            let amount = match take_dose_request.amount.map(|a| a.to_rational()) {
+                None => None,
+                Some(Ok(a)) => Some(a),
+                Some(Err(e)) => return respond_error(400, &format!("invalid value for \"amount\": {}", e)),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, _now| {
+                let index = parse_id(&id_str, data)?;
+                actions::take_dose(&mut data[index], amount)
+            }).await;
+            respond_modified_drug(&auth.patient, result, &id_str).await
+        },
+        ["drugs", id_str, "doses"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let id_str = id_str.to_string();
+            let log_request: LogDoseRequest = match read_json_body(request).await {
+                Ok(lr) => lr,
+                Err(f) => return f.respond(),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, events, now| {
+                let index = parse_id(&id_str, data)?;
+                let today = now.with_timezone(&Local).date_naive();
+                let record = DoseRecord {
+                    date: log_request.date.unwrap_or(today),
+                    slot: log_request.slot,
+                    status: log_request.status,
+                };
+                actions::log_dose(&mut data[index], events, record, today)
+            }).await;
+            respond_modified_drug(&auth.patient, result, &id_str).await
+        },
+        ["drugs", id_str, "transfer"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let id_str = id_str.to_string();
+            let transfer_request: TransferRequest = match read_json_body(request).await {
+                Ok(tr) => tr,
+                Err(f) => return f.respond(),
+            };
+            let amount = match transfer_request.amount.to_rational() {
+                Ok(a) => a,
+                Err(e) => return respond_error(400, &format!("invalid value for \"amount\": {}", e)),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, _now| {
+                let index = parse_id(&id_str, data)?;
+                actions::transfer(&mut data[index], amount, &transfer_request.from, &transfer_request.to)
+            }).await;
+            respond_modified_drug(&auth.patient, result, &id_str).await
+        },
+        ["take-days"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let take_days_request: TakeDaysRequest = match read_json_body(request).await {
+                Ok(tdr) => tdr,
+                Err(f) => return f.respond(),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, now| {
+                actions::take_days(data, take_days_request.days, now.with_timezone(&Local).date_naive())
+            }).await;
+            respond_modified_list(&auth.patient, result).await
+        },
+        ["discard-expired"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, now| {
+                Ok(actions::discard_expired(data, now.with_timezone(&Local).date_naive()))
+            }).await;
+            respond_modified_list(&auth.patient, result).await
+        },
+        ["undo"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let undo_request: UndoRequest = match read_json_body(request).await {
+                Ok(ur) => ur,
+                Err(f) => return f.respond(),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, events, _now| {
+                actions::undo(data, events, undo_request.operation)
+            }).await;
+            let (data, modification) = match result {
+                Ok(dm) => dm,
+                Err(e) => return ApiFailure::from(e).respond(),
+            };
+            let drug_list = match api_drug_list(&auth.patient, &data).await {
+                Ok(list) => list,
+                Err(f) => return f.respond(),
+            };
+            respond_json(&UndoResponse {
+                undone_operation: modification.undoes().expect("undo without undone operation"),
+                drug_list,
+            })
+        },
+        ["planner"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let horizon_days = match planner::parse_horizon_days(query_value(&request, "days").as_deref()) {
+                Ok(hd) => hd,
+                Err(e) => return respond_error(400, &e),
+            };
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let now = Utc::now();
+            let drugs = match load_drugs_to_display(&auth.patient, &data, now).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(d) => d,
+            };
+            match planner::plan_visits(&drugs, now.with_timezone(&Local).date_naive(), horizon_days) {
+                Ok(visits) => respond_json(&visits),
+                Err(e) => respond_error(400, e),
+            }
+        },
+        ["doses"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let now = Utc::now();
+            let date = match query_value(&request, "date") {
+                None => now.with_timezone(&Local).date_naive(),
+                Some(date_str) => match date_str.parse() {
+                    Ok(d) => d,
+                    Err(_) => return respond_error(400, "invalid value for \"date\""),
+                },
+            };
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let events = match journal::load_journal(&auth.patient).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(j) => j,
+            };
+            respond_json(&adherence::scheduled_doses(&data, &events, date))
+        },
+        ["adherence"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let days = match adherence::parse_report_days(query_value(&request, "days").as_deref()) {
+                Ok(d) => d,
+                Err(msg) => return respond_error(400, &msg),
+            };
+            let mut data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let now = Utc::now();
+            catch_up_consumption(&mut data, now);
+            let events = match journal::load_journal(&auth.patient).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(j) => j,
+            };
+            let today = now.with_timezone(&Local).date_naive();
+            respond_json(&adherence::adherence_report(&data, &events, today, days))
+        },
+        ["ingredients"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let now = Utc::now();
+            let date = match query_value(&request, "date") {
+                None => now.with_timezone(&Local).date_naive(),
+                Some(date_str) => match date_str.parse() {
+                    Ok(d) => d,
+                    Err(_) => return respond_error(400, "invalid value for \"date\""),
+                },
+            };
+            let mut data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            catch_up_consumption(&mut data, now);
+            let events = match journal::load_journal(&auth.patient).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(j) => j,
+            };
+            let taken_as_needed = journal::doses_taken_on(&events, date);
+            let limits = ingredients::configured_limits().await;
+            respond_json(&ingredients::daily_totals(&data, date, &taken_as_needed, &limits))
+        },
+        ["patients"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let names: Vec<&str> = auth.patients.iter()
+                .map(|p| p.name.as_str())
+                .collect();
+            respond_json(&names)
+        },
+        ["interactions"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let drugs = match load_drugs_to_display(&auth.patient, &data, Utc::now()).await {
+                None => return respond_error(500, "failed to load journal"),
+                Some(d) => d,
+            };
+            let table = match interactions::load_configured_table().await {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("{}", e);
+                    return respond_error(500, &e);
+                },
+            };
+            let shown: Vec<Drug> = drugs.into_iter()
+                .map(|dtd| dtd.drug)
+                .collect();
+            respond_json(&interactions::check_interactions(&shown, &table))
+        },
+        ["organizer"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let now = Utc::now();
+            let start = match organizer::parse_start(query_value(&request, "start").as_deref(), now.with_timezone(&Local).date_naive()) {
+                Ok(s) => s,
+                Err(msg) => return respond_error(400, msg),
+            };
+            let mut data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            catch_up_consumption(&mut data, now);
+            respond_json(&organizer::fill_plan(&data, start))
+        },
+        ["organizer", "fill"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let fill_request: FillOrganizerRequest = match read_json_body(request).await {
+                Ok(fr) => fr,
+                Err(f) => return f.respond(),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, now| {
+                let start = fill_request.start
+                    .unwrap_or_else(|| now.with_timezone(&Local).date_naive());
+                Ok(actions::fill_organizer(data, organizer::check_start(start)?))
+            }).await;
+            respond_modified_list(&auth.patient, result).await
+        },
+        ["travel"] => {
+            if request.method() != Method::GET {
+                return respond_error(405, "try one of: GET");
+            }
+            let trip = match travel::Trip::from_values(&query_values(&request)) {
+                Ok(t) => t,
+                Err(msg) => return respond_error(400, &msg),
+            };
+            let mut data = match load_data(&auth.patient).await {
+                None => return respond_error(500, "failed to load data"),
+                Some(d) => d,
+            };
+            let now = Utc::now();
+            catch_up_consumption(&mut data, now);
+            respond_json(&travel::packing_list(&data, now.with_timezone(&Local).date_naive(), &trip))
+        },
+        ["travel", "pack"] => {
+            if request.method() != Method::POST {
+                return respond_error(405, "try one of: POST");
+            }
+            let pack_request: PackTripRequest = match read_json_body(request).await {
+                Ok(pr) => pr,
+                Err(f) => return f.respond(),
+            };
+            let trip = match travel::Trip::new(pack_request.start, pack_request.end, pack_request.buffer_days) {
+                Ok(t) => t,
+                Err(msg) => return respond_error(400, &msg),
+            };
+            let result = modify_data(&auth.patient, auth.token_label.as_deref(), |data, _events, now| {
+                let today = now.with_timezone(&Local).date_naive();
+                Ok(actions::pack_trip(data, today, &trip, pack_request.location.as_deref()))
+            }).await;
+            respond_modified_list(&auth.patient, result).await
+        },
+        _ => respond_error(404, "unknown API endpoint"),
+    }
+}
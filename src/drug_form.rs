@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{DateTime, Local, NaiveDate, Utc, Weekday};
+use hyper::{Body, Request, Response};
+use num_rational::Rational64;
+use num_traits::{One, Zero};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing::error;
+
+use crate::{filters, get_drug_index, load_data, respond_404, respond_500, Auth};
+use crate::model::{Drug, DrugComponent, Prescription, ReplenishmentOverrides};
+use crate::schedule::{validate_dosage_plan, DailyDosage, DosagePhase, Frequency};
+use crate::units::{parse_count_unit, Unit, COUNT_UNITS};
+use crate::util::parse_amount;
+
+
+/// The number of empty component rows offered by the form.
+const BLANK_COMPONENT_ROWS: usize = 3;
+
+/// The number of empty dosage plan rows offered by the form.
+const BLANK_PHASE_ROWS: usize = 2;
+
+
+static PHOTO_FILENAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(
+    "^[A-Za-z0-9-_]+[.][A-Za-z0-9]+$"
+).expect("failed to compile regex"));
+
+
+#[derive(Template)]
+#[template(path = "drug_form.html", escape = "none")]
+struct DrugFormTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub drug_id: Option<u64>,
+    pub drug: Drug,
+    pub blank_component_indexes: Vec<usize>,
+    pub blank_phase_indexes: Vec<usize>,
+    pub frequency_form: FrequencyForm,
+    pub unit_symbols: Vec<&'static str>,
+}
+
+/// The values of the frequency fields in the drug form.
+struct FrequencyForm {
+    pub kind: &'static str,
+    pub weekdays: Vec<WeekdayCheckbox>,
+    pub interval: String,
+    pub start: String,
+    pub day: String,
+}
+
+struct WeekdayCheckbox {
+    pub key: String,
+    pub checked: bool,
+}
+
+
+fn blank_drug() -> Drug {
+    Drug::new(
+        String::new(),
+        Vec::new(),
+        String::new(),
+        Zero::zero(),
+        Zero::zero(),
+        Zero::zero(),
+        Zero::zero(),
+        Zero::zero(),
+        One::one(),
+        One::one(),
+        true,
+        None,
+        None,
+        true,
+        Drug::default_in_replenishment_cycle(),
+    )
+}
+
+fn get_amount(opts: &HashMap<String, String>, key: &str) -> Result<Rational64, String> {
+    let value_str = opts.get(key)
+        .ok_or_else(|| format!("missing value for {:?}", key))?;
+    let value = parse_amount(value_str)
+        .map_err(|e| format!("invalid value for {:?}: {}", key, e))?;
+    if value < Zero::zero() {
+        return Err(format!("{:?} must not be negative", key));
+    }
+    Ok(value)
+}
+
+/// Returns the non-negative whole number entered for `key`, or `None` if the field has been left
+/// empty.
+fn get_optional_count(opts: &HashMap<String, String>, key: &str) -> Result<Option<i64>, String> {
+    let value_str = opts.get(key)
+        .map(|v| v.trim())
+        .unwrap_or("");
+    if value_str.is_empty() {
+        return Ok(None);
+    }
+    match value_str.parse() {
+        Ok(v) if v >= 0 => Ok(Some(v)),
+        _ => Err(format!("invalid value for {:?}", key)),
+    }
+}
+
+pub(crate) fn get_optional_date(opts: &HashMap<String, String>, key: &str) -> Result<Option<NaiveDate>, String> {
+    let value_str = opts.get(key)
+        .map(|v| v.trim())
+        .unwrap_or("");
+    if value_str.is_empty() {
+        return Ok(None);
+    }
+    value_str.parse()
+        .map(Some)
+        .map_err(|_| format!("invalid value for {:?}", key))
+}
+
+/// Reads the prescription fields; the prescription is removed if its issue date is left empty.
+fn get_prescription(opts: &HashMap<String, String>) -> Result<Option<Prescription>, String> {
+    let issued = match get_optional_date(opts, "prescription-issued")? {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let prescriber = opts.get("prescription-prescriber")
+        .map(|p| p.trim())
+        .unwrap_or("");
+    let expires = get_optional_date(opts, "prescription-expires")?;
+    if let Some(exp) = expires {
+        if exp < issued {
+            return Err("prescription must not expire before it is issued".to_owned());
+        }
+    }
+    let refills_remaining: u32 = opts.get("prescription-refills")
+        .and_then(|r| r.trim().parse().ok())
+        .ok_or("invalid value for \"prescription-refills\"")?;
+    Ok(Some(Prescription::new(prescriber.to_owned(), issued, expires, refills_remaining)))
+}
+
+fn get_photo(opts: &HashMap<String, String>, key: &str) -> Result<Option<String>, String> {
+    let value = match opts.get(key) {
+        Some(v) => v.trim(),
+        None => return Ok(None),
+    };
+    if value.is_empty() {
+        Ok(None)
+    } else if PHOTO_FILENAME_REGEX.is_match(value) {
+        Ok(Some(value.to_owned()))
+    } else {
+        Err(format!("invalid file name for {:?}", key))
+    }
+}
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun,
+];
+
+fn weekday_key(weekday: Weekday) -> String {
+    weekday.to_string().to_lowercase()
+}
+
+impl FrequencyForm {
+    fn from_frequency(frequency: &Frequency) -> Self {
+        let mut form = Self {
+            kind: "daily",
+            weekdays: WEEKDAYS.iter()
+                .map(|wd| WeekdayCheckbox { key: weekday_key(*wd), checked: false })
+                .collect(),
+            interval: String::new(),
+            start: Local::now().date_naive().to_string(),
+            day: String::new(),
+        };
+        match frequency {
+            Frequency::Daily => {},
+            Frequency::Weekdays { weekdays } => {
+                form.kind = "weekdays";
+                for (checkbox, weekday) in form.weekdays.iter_mut().zip(WEEKDAYS.iter()) {
+                    checkbox.checked = weekdays.contains(weekday);
+                }
+            },
+            Frequency::EveryNDays { interval, start } => {
+                form.kind = "every-n-days";
+                form.interval = interval.to_string();
+                form.start = start.to_string();
+            },
+            Frequency::Monthly { day } => {
+                form.kind = "monthly";
+                form.day = day.to_string();
+            },
+        }
+        form
+    }
+}
+
+fn get_frequency(opts: &HashMap<String, String>) -> Result<Frequency, String> {
+    let kind = opts.get("frequency")
+        .map(|f| f.as_str())
+        .unwrap_or("daily");
+    match kind {
+        "daily" => Ok(Frequency::Daily),
+        "weekdays" => {
+            let weekdays: Vec<Weekday> = WEEKDAYS.iter()
+                .filter(|wd| opts.contains_key(&format!("frequency-weekday-{}", weekday_key(**wd))))
+                .copied()
+                .collect();
+            if weekdays.is_empty() {
+                return Err("at least one weekday must be selected".to_owned());
+            }
+            Ok(Frequency::Weekdays { weekdays })
+        },
+        "every-n-days" => {
+            let interval: u32 = opts.get("frequency-interval")
+                .and_then(|i| i.trim().parse().ok())
+                .filter(|i| *i > 0)
+                .ok_or("invalid value for \"frequency-interval\"")?;
+            let start: NaiveDate = opts.get("frequency-start")
+                .and_then(|s| s.trim().parse().ok())
+                .ok_or("invalid value for \"frequency-start\"")?;
+            Ok(Frequency::EveryNDays { interval, start })
+        },
+        "monthly" => {
+            let day: u32 = opts.get("frequency-day")
+                .and_then(|d| d.trim().parse().ok())
+                .filter(|d| (1..=31).contains(d))
+                .ok_or("invalid value for \"frequency-day\"")?;
+            Ok(Frequency::Monthly { day })
+        },
+        _ => Err("invalid value for \"frequency\"".to_owned()),
+    }
+}
+
+fn get_dosage_plan(opts: &HashMap<String, String>) -> Result<Vec<DosagePhase>, String> {
+    let mut phases = Vec::new();
+    for i in 0.. {
+        let start_key = format!("phase{}-start", i);
+        let end_key = format!("phase{}-end", i);
+        let start_str = match opts.get(&start_key) {
+            Some(s) => s.trim(),
+            None => break,
+        };
+        let end_str = opts.get(&end_key)
+            .map(|e| e.trim())
+            .unwrap_or("");
+        if start_str.is_empty() && end_str.is_empty() {
+            continue;
+        }
+        let start: NaiveDate = start_str.parse()
+            .map_err(|_| format!("invalid value for {:?}", start_key))?;
+        let end: NaiveDate = end_str.parse()
+            .map_err(|_| format!("invalid value for {:?}", end_key))?;
+        let dosage = DailyDosage {
+            morning: get_amount(opts, &format!("phase{}-morning", i))?,
+            noon: get_amount(opts, &format!("phase{}-noon", i))?,
+            evening: get_amount(opts, &format!("phase{}-evening", i))?,
+            night: get_amount(opts, &format!("phase{}-night", i))?,
+        };
+        phases.push(DosagePhase { start, end, dosage });
+    }
+    validate_dosage_plan(&phases)?;
+    phases.sort_by_key(|p| p.start);
+    Ok(phases)
+}
+
+/// Parses the unit of the active ingredient `generic_name`.
+///
+/// Known units are stored by their usual symbol and count units in lowercase. Other units are only
+/// accepted if `previous`, the drug being edited, already has the ingredient in exactly that unit.
+fn get_component_unit(opts: &HashMap<String, String>, key: &str, generic_name: &str, previous: Option<&Drug>) -> Result<String, String> {
+    let unit_str = opts.get(key)
+        .map(|u| u.trim())
+        .unwrap_or("");
+    if let Some(unit) = Unit::parse(unit_str) {
+        return Ok(unit.symbol().to_owned());
+    }
+    if let Some(unit) = parse_count_unit(unit_str) {
+        return Ok(unit.to_owned());
+    }
+    let previously_stored = previous
+        .map(|drug| drug.components().iter()
+            .any(|c| c.generic_name().trim() == generic_name && c.unit().trim() == unit_str))
+        .unwrap_or(false);
+    if previously_stored {
+        return Ok(unit_str.to_owned());
+    }
+    Err(format!(
+        "invalid value for {:?}: unknown unit {:?}; known units are: {}, {}",
+        key, unit_str, Unit::symbols(), COUNT_UNITS.join(", "),
+    ))
+}
+
+/// Assembles a drug from the values submitted through the drug form.
+///
+/// `previous` is the drug being edited, if any. If automatic consumption tracking is enabled, the
+/// entered stock is taken to be the one at `now`.
+pub(crate) fn parse_drug_form(opts: &HashMap<String, String>, previous: Option<&Drug>, now: DateTime<Utc>) -> Result<Drug, String> {
+    let trade_name = opts.get("trade-name")
+        .map(|s| s.trim())
+        .unwrap_or("");
+    if trade_name.is_empty() {
+        return Err("\"trade-name\" must not be empty".to_owned());
+    }
+
+    let mut components = Vec::new();
+    for i in 0.. {
+        let name_key = format!("component{}-generic-name", i);
+        let generic_name = match opts.get(&name_key) {
+            Some(gn) => gn.trim(),
+            None => break,
+        };
+        if generic_name.is_empty() {
+            continue;
+        }
+        let amount = get_amount(opts, &format!("component{}-amount", i))?;
+        let unit = get_component_unit(opts, &format!("component{}-unit", i), generic_name, previous)?;
+        components.push(DrugComponent::new(generic_name.to_owned(), amount, unit));
+    }
+
+    let description = opts.get("description")
+        .map(|d| d.replace("\r\n", "\n"))
+        .unwrap_or_default();
+
+    let units_per_package = get_amount(opts, "units-per-package")?;
+    let packages_per_prescription = get_amount(opts, "packages-per-prescription")?;
+
+    let mut drug = Drug::new(
+        trade_name.to_owned(),
+        components,
+        description,
+        get_amount(opts, "remaining")?,
+        get_amount(opts, "dosage-morning")?,
+        get_amount(opts, "dosage-noon")?,
+        get_amount(opts, "dosage-evening")?,
+        get_amount(opts, "dosage-night")?,
+        units_per_package,
+        packages_per_prescription,
+        opts.contains_key("show"),
+        get_photo(opts, "obverse-photo")?,
+        get_photo(opts, "reverse-photo")?,
+        opts.contains_key("is-pill"),
+        opts.contains_key("in-replenishment-cycle"),
+    );
+    drug.set_frequency(get_frequency(opts)?);
+    let min_stock_str = opts.get("min-stock")
+        .map(|ms| ms.trim())
+        .unwrap_or("");
+    let min_stock = if min_stock_str.is_empty() {
+        None
+    } else {
+        Some(get_amount(opts, "min-stock")?)
+    };
+    drug.set_as_needed(opts.contains_key("as-needed"));
+    let overrides = ReplenishmentOverrides {
+        lead_time_days: get_optional_count(opts, "lead-time-days")?,
+        safety_stock_weeks: get_optional_count(opts, "safety-stock-weeks")?,
+        doctor_visit_days: get_optional_count(opts, "doctor-visit-days")?,
+    };
+    drug.set_prescription(get_prescription(opts)?);
+    drug.set_primary_location(
+        opts.get("primary-location")
+            .map(|pl| pl.trim())
+            .filter(|pl| !pl.is_empty())
+            .map(|pl| pl.to_owned())
+    );
+    drug.set_replenishment(min_stock, overrides);
+    drug.set_dosage_plan(get_dosage_plan(opts)?, opts.contains_key("hide-when-finished"));
+    drug.set_tracks_consumption(opts.contains_key("track-consumption"), now);
+    Ok(drug)
+}
+
+
+pub(crate) async fn handle_get_drug_form(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let drug_opt = if query_values.contains_key("drug-id") || query_values.contains_key("drug-index") {
+        let mut data = match load_data(&auth.patient).await {
+            None => return respond_500(),
+            Some(d) => d,
+        };
+        let index = match get_drug_index(&query_values, &data) {
+            Ok(i) => i,
+            Err(_) => return respond_404(),
+        };
+        // show the stock as it should be by now
+        let mut drug = data.swap_remove(index);
+        drug.consume_until(Utc::now());
+        Some(drug)
+    } else {
+        None
+    };
+    let drug_id = drug_opt.as_ref().map(|d| d.id());
+    let drug = drug_opt.unwrap_or_else(blank_drug);
+
+    let first_blank = drug.components().len();
+    let first_blank_phase = drug.dosage_plan().len();
+    let frequency_form = FrequencyForm::from_frequency(drug.frequency());
+    let template = DrugFormTemplate {
+        token,
+        patient: &auth.patient.name,
+        drug_id,
+        drug,
+        blank_component_indexes: (first_blank..first_blank+BLANK_COMPONENT_ROWS).collect(),
+        blank_phase_indexes: (first_blank_phase..first_blank_phase+BLANK_PHASE_ROWS).collect(),
+        frequency_form,
+        unit_symbols: Unit::ALL.iter().map(|u| u.symbol()).chain(COUNT_UNITS).collect(),
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drug_opts(menthol_unit: &str) -> HashMap<String, String> {
+        [
+            ("trade-name", "Aspirin"), ("frequency", "daily"),
+            ("remaining", "10"), ("units-per-package", "20"), ("packages-per-prescription", "1"),
+            ("dosage-morning", "1"), ("dosage-noon", "0"), ("dosage-evening", "0"), ("dosage-night", "0"),
+            ("component0-generic-name", "acetylsalicylic acid"), ("component0-amount", "100"), ("component0-unit", " MCG "),
+            ("component1-generic-name", "menthol"), ("component1-amount", "1"), ("component1-unit", menthol_unit),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_component_units() {
+        let drug = parse_drug_form(&drug_opts(" Drops "), None, Utc::now()).unwrap();
+        let units: Vec<&str> = drug.components().iter().map(|c| c.unit()).collect();
+        assert_eq!(units, vec!["\u{B5}g", "drops"]);
+
+        assert!(parse_drug_form(&drug_opts("mgg"), None, Utc::now()).is_err());
+        assert!(parse_drug_form(&drug_opts(""), None, Utc::now()).is_err());
+
+        // units stored before they were validated are kept when the drug is edited
+        let previous = Drug::for_test(1, 10)
+            .with_components(vec![DrugComponent::new("menthol".to_owned(), One::one(), "Tr.".to_owned())]);
+        let drug = parse_drug_form(&drug_opts("Tr."), Some(&previous), Utc::now()).unwrap();
+        assert_eq!(drug.components()[1].unit(), "Tr.");
+        assert!(parse_drug_form(&drug_opts("Tr"), Some(&previous), Utc::now()).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use tracing::error;
+
+use crate::{filters, load_data, respond_500, Auth};
+use crate::model::{Drug, StockBatch};
+
+
+/// A drug with stock that has expired and should be discarded.
+#[derive(Clone, Debug)]
+struct ExpiredStock {
+    drug_id: u64,
+    trade_name: String,
+    batches: Vec<StockBatch>,
+}
+
+#[derive(Template)]
+#[template(path = "expired.html", escape = "none")]
+struct ExpiredTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub today: NaiveDate,
+    pub drugs: Vec<ExpiredStock>,
+}
+
+
+impl ExpiredStock {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn batches(&self) -> &[StockBatch] { &self.batches }
+}
+
+
+fn expired_stock(data: &[Drug], today: NaiveDate) -> Vec<ExpiredStock> {
+    data.iter()
+        .filter_map(|drug| {
+            let batches: Vec<StockBatch> = drug.expired_batches(today)
+                .into_iter()
+                .cloned()
+                .collect();
+            if batches.is_empty() {
+                None
+            } else {
+                Some(ExpiredStock {
+                    drug_id: drug.id(),
+                    trade_name: drug.trade_name().to_owned(),
+                    batches,
+                })
+            }
+        })
+        .collect()
+}
+
+
+pub(crate) async fn handle_get_expired(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    let today = Utc::now().with_timezone(&Local).date_naive();
+
+    let template = ExpiredTemplate {
+        token,
+        patient: &auth.patient.name,
+        today,
+        drugs: expired_stock(&data, today),
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
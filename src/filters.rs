@@ -1,3 +1,4 @@
+#[allow(clippy::single_component_path_imports)]
 use askama;
 use num_rational::Rational64;
 
@@ -19,3 +20,136 @@ pub(crate) fn frac2float(frac: Rational64) -> askama::Result<f64> {
     let denom_f64 = *frac.denom() as f64;
     Ok(numer_f64 / denom_f64)
 }
+
+/// Formats a fraction as a decimal number rounded to two places, for amounts that are only
+/// estimates.
+pub(crate) fn frac2rounded(frac: Rational64) -> askama::Result<String> {
+    let rounded = (frac * Rational64::from_integer(100)).round() / Rational64::from_integer(100);
+    frac2dec(rounded)
+}
+
+/// Formats a fraction as an exact decimal number if it has one and rounded to two places
+/// otherwise, for amounts of active ingredients.
+pub(crate) fn frac2amount(frac: Rational64) -> askama::Result<String> {
+    let dec = frac2dec(frac)?;
+    if dec.contains('/') {
+        frac2rounded(frac)
+    } else {
+        Ok(dec)
+    }
+}
+
+/// Formats a non-negative fraction as a whole number followed by a fraction sign such as "½", the
+/// way pill halves and quarters are usually written.
+pub(crate) fn frac2mixed(frac: Rational64) -> askama::Result<String> {
+    let whole = frac.trunc().to_integer();
+    let rest = frac.fract();
+    let rest_str = match (*rest.numer(), *rest.denom()) {
+        (0, _) => return Ok(whole.to_string()),
+        (1, 2) => "\u{BD}".to_owned(),
+        (1, 4) => "\u{BC}".to_owned(),
+        (3, 4) => "\u{BE}".to_owned(),
+        (1, 3) => "\u{2153}".to_owned(),
+        (2, 3) => "\u{2154}".to_owned(),
+        (n, d) => return if whole == 0 {
+            Ok(format!("{}/{}", n, d))
+        } else {
+            Ok(format!("{} {}/{}", whole, n, d))
+        },
+    };
+    if whole == 0 {
+        Ok(rest_str)
+    } else {
+        Ok(format!("{}{}", whole, rest_str))
+    }
+}
+
+pub(crate) fn frac2dec(frac: Rational64) -> askama::Result<String> {
+    // denominators consisting only of the factors 2 and 5 have a finite decimal representation
+    let mut denom = *frac.denom();
+    let mut power_of_ten: u32 = 0;
+    let mut factor: Option<i64> = Some(1);
+    while denom % 10 == 0 {
+        denom /= 10;
+        power_of_ten += 1;
+    }
+    while denom % 2 == 0 {
+        denom /= 2;
+        factor = factor.and_then(|f| f.checked_mul(5));
+        power_of_ten += 1;
+    }
+    while denom % 5 == 0 {
+        denom /= 5;
+        factor = factor.and_then(|f| f.checked_mul(2));
+        power_of_ten += 1;
+    }
+    if denom != 1 {
+        return frac2str(frac);
+    }
+
+    let scaled = match factor.and_then(|f| frac.numer().checked_mul(f)) {
+        Some(s) => s,
+        None => return frac2str(frac),
+    };
+    let digits = scaled.unsigned_abs().to_string();
+    let sign = if scaled < 0 { "-" } else { "" };
+    let power_of_ten: usize = power_of_ten.try_into().unwrap();
+    if power_of_ten == 0 {
+        Ok(format!("{}{}", sign, digits))
+    } else if digits.len() > power_of_ten {
+        let (int_part, frac_part) = digits.split_at(digits.len() - power_of_ten);
+        Ok(format!("{}{}.{}", sign, int_part, frac_part))
+    } else {
+        Ok(format!("{}0.{:0>width$}", sign, digits, width = power_of_ten))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_rational::Rational64;
+
+    fn test_frac2dec(expected: &str, numer: i64, denom: i64) {
+        let dec = super::frac2dec(Rational64::new(numer, denom))
+            .unwrap();
+        assert_eq!(expected, dec);
+    }
+
+    #[test]
+    fn test_frac2dec_finite() {
+        test_frac2dec("0", 0, 1);
+        test_frac2dec("5", 5, 1);
+        test_frac2dec("0.5", 1, 2);
+        test_frac2dec("-0.5", -1, 2);
+        test_frac2dec("0.025", 1, 40);
+        test_frac2dec("12.8", 64, 5);
+        test_frac2dec("0.128", 16, 125);
+    }
+
+    #[test]
+    fn test_frac2rounded() {
+        assert_eq!("0.33", super::frac2rounded(Rational64::new(1, 3)).unwrap());
+        assert_eq!("2", super::frac2rounded(Rational64::new(2, 1)).unwrap());
+        assert_eq!("0.1", super::frac2rounded(Rational64::new(1, 10)).unwrap());
+    }
+
+    #[test]
+    fn test_frac2amount() {
+        assert_eq!("0.125", super::frac2amount(Rational64::new(1, 8)).unwrap());
+        assert_eq!("33.33", super::frac2amount(Rational64::new(100, 3)).unwrap());
+    }
+
+    #[test]
+    fn test_frac2mixed() {
+        assert_eq!("2", super::frac2mixed(Rational64::new(2, 1)).unwrap());
+        assert_eq!("\u{BD}", super::frac2mixed(Rational64::new(1, 2)).unwrap());
+        assert_eq!("1\u{BE}", super::frac2mixed(Rational64::new(7, 4)).unwrap());
+        assert_eq!("1 1/5", super::frac2mixed(Rational64::new(6, 5)).unwrap());
+    }
+
+    #[test]
+    fn test_frac2dec_infinite() {
+        test_frac2dec("1/3", 1, 3);
+        test_frac2dec("-7/6", -7, 6);
+    }
+}
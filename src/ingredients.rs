@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use num_rational::Rational64;
+use num_traits::Zero;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{catch_up_consumption, filters, journal, load_data, respond_400, respond_500, Auth, CONFIG};
+use crate::model::Drug;
+use crate::units::Quantity;
+
+
+/// The amount of an active ingredient contained in the daily dosage of one drug.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct IngredientSource {
+    drug_id: u64,
+    trade_name: String,
+    amount: Rational64,
+}
+
+/// The total amount of an active ingredient taken on one day, summed across all drugs containing
+/// it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct IngredientTotal {
+    generic_name: String,
+    amount: Rational64,
+    unit: String,
+    sources: Vec<IngredientSource>,
+    max_daily: Option<Rational64>,
+    exceeded: bool,
+}
+
+#[derive(Template)]
+#[template(path = "ingredients.html", escape = "none")]
+struct IngredientsTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub date: NaiveDate,
+    pub totals: Vec<IngredientTotal>,
+}
+
+
+impl IngredientSource {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn amount(&self) -> Rational64 { self.amount }
+}
+
+impl IngredientTotal {
+    pub fn generic_name(&self) -> &str { &self.generic_name }
+    pub fn amount(&self) -> Rational64 { self.amount }
+    pub fn unit(&self) -> &str { &self.unit }
+    pub fn sources(&self) -> &[IngredientSource] { &self.sources }
+    pub fn max_daily(&self) -> Option<Rational64> { self.max_daily }
+    pub fn exceeded(&self) -> bool { self.exceeded }
+
+    /// A warning to display if the maximum daily dose is exceeded.
+    pub fn warning(&self) -> Option<String> {
+        let max_daily = self.max_daily?;
+        if !self.exceeded {
+            return None;
+        }
+        let format_amount = |amount: Rational64| filters::frac2amount(amount)
+            .unwrap_or_default();
+        let trade_names: Vec<&str> = self.sources.iter()
+            .map(|source| source.trade_name.as_str())
+            .collect();
+        Some(format!(
+            "{} {} {} per day exceeds the maximum of {} {} ({})",
+            self.generic_name, format_amount(self.amount), self.unit,
+            format_amount(max_daily), self.unit, trade_names.join(", "),
+        ))
+    }
+}
+
+
+/// The key by which active ingredients are matched: their generic name, ignoring case.
+pub(crate) fn ingredient_key(generic_name: &str) -> String {
+    generic_name.trim().to_lowercase()
+}
+
+/// Parses the configured maximum daily doses, keyed by generic name, converting them into base
+/// units.
+pub(crate) fn parse_limits(limits: &HashMap<String, String>) -> Result<HashMap<String, Quantity>, String> {
+    limits.iter()
+        .map(|(generic_name, limit)| {
+            let quantity = Quantity::parse(limit)
+                .map_err(|e| format!("invalid maximum daily dose of {:?}: {}", generic_name, e))?;
+            Ok((ingredient_key(generic_name), quantity.to_base()))
+        })
+        .collect()
+}
+
+/// Obtains the maximum daily doses from the configuration.
+pub(crate) async fn configured_limits() -> HashMap<String, Quantity> {
+    let config_guard = CONFIG
+        .get().expect("CONFIG not set")
+        .read().await;
+    match parse_limits(&config_guard.max_daily_doses) {
+        Ok(limits) => limits,
+        Err(e) => {
+            // validated at startup
+            error!("{}", e);
+            HashMap::new()
+        },
+    }
+}
+
+/// Sums the amounts of each active ingredient taken on `date` across all shown drugs.
+///
+/// Drugs taken as needed contribute the amounts in `taken_as_needed` (see
+/// [`journal::doses_taken_on`]); all other drugs contribute their dosage of that day. Amounts are
+/// converted into the base unit of their dimension (e.g. milligrams for masses); ingredients
+/// given in unknown units are only summed with amounts in the same unit.
+pub(crate) fn daily_totals(
+    data: &[Drug],
+    date: NaiveDate,
+    taken_as_needed: &HashMap<u64, Rational64>,
+    limits: &HashMap<String, Quantity>,
+) -> Vec<IngredientTotal> {
+    let mut totals: Vec<IngredientTotal> = Vec::new();
+    for drug in data.iter().filter(|d| d.show()) {
+        let units_taken = if drug.as_needed() {
+            taken_as_needed.get(&drug.id()).copied().unwrap_or_else(Zero::zero)
+        } else {
+            drug.dosage_on(date).total()
+        };
+        if units_taken.is_zero() {
+            continue;
+        }
+
+        for component in drug.components() {
+            let (amount, unit) = match component.quantity() {
+                Some(quantity) => {
+                    let base = Quantity::new(quantity.amount * units_taken, quantity.unit).to_base();
+                    (base.amount, base.unit.symbol().to_owned())
+                },
+                None => (component.amount() * units_taken, component.unit().trim().to_owned()),
+            };
+            let key = ingredient_key(component.generic_name());
+            let source = IngredientSource {
+                drug_id: drug.id(),
+                trade_name: drug.trade_name().to_owned(),
+                amount,
+            };
+            let existing = totals.iter_mut()
+                .find(|t| ingredient_key(&t.generic_name) == key && t.unit == unit);
+            match existing {
+                Some(total) => {
+                    total.amount += amount;
+                    total.sources.push(source);
+                },
+                None => totals.push(IngredientTotal {
+                    generic_name: component.generic_name().trim().to_owned(),
+                    amount,
+                    unit,
+                    sources: vec![source],
+                    max_daily: None,
+                    exceeded: false,
+                }),
+            }
+        }
+    }
+
+    for total in &mut totals {
+        if let Some(limit) = limits.get(&ingredient_key(&total.generic_name)) {
+            if limit.unit.symbol() == total.unit {
+                total.max_daily = Some(limit.amount);
+                total.exceeded = total.amount > limit.amount;
+            }
+        }
+    }
+    totals.sort_by_key(|t| ingredient_key(&t.generic_name));
+    totals
+}
+
+
+pub(crate) async fn handle_get_ingredients(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let now = Utc::now();
+    let date: NaiveDate = match query_values.get("date") {
+        None => now.with_timezone(&Local).date_naive(),
+        Some(date_str) => match date_str.parse() {
+            Ok(d) => d,
+            Err(_) => return respond_400("invalid value for \"date\""),
+        },
+    };
+
+    let mut data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    catch_up_consumption(&mut data, now);
+    let events = match journal::load_journal(&auth.patient).await {
+        None => return respond_500(),
+        Some(e) => e,
+    };
+    let taken_as_needed = journal::doses_taken_on(&events, date);
+    let limits = configured_limits().await;
+
+    let template = IngredientsTemplate {
+        token,
+        patient: &auth.patient.name,
+        date,
+        totals: daily_totals(&data, date, &taken_as_needed, &limits),
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::model::DrugComponent;
+    use super::*;
+
+    fn drug(id: u64, components: Vec<DrugComponent>, morning: i64, as_needed: bool) -> Drug {
+        let mut drug = Drug::for_test(id, 10)
+            .with_components(components)
+            .with_dosage(Rational64::from_integer(morning), Zero::zero(), Zero::zero(), Zero::zero());
+        drug.set_as_needed(as_needed);
+        drug
+    }
+
+    fn component(generic_name: &str, amount: i64, unit: &str) -> DrugComponent {
+        DrugComponent::new(generic_name.to_owned(), Rational64::from_integer(amount), unit.to_owned())
+    }
+
+    #[test]
+    fn test_daily_totals() {
+        let data = vec![
+            drug(1, vec![component("Paracetamol", 1, "g")], 3, false),
+            drug(2, vec![component("paracetamol", 500, "mg"), component("Caffeine", 50, "mg")], 0, true),
+            drug(3, vec![component("Colecalciferol", 1000, "IU")], 1, false),
+        ];
+        let date = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        let taken_as_needed: HashMap<u64, Rational64> = [(2, Rational64::from_integer(3))].into_iter().collect();
+        let limits = parse_limits(&[
+            ("PARACETAMOL".to_owned(), "4 g".to_owned()),
+            ("colecalciferol".to_owned(), "4000 IU".to_owned()),
+        ].into_iter().collect()).unwrap();
+
+        let totals = daily_totals(&data, date, &taken_as_needed, &limits);
+        let summary: Vec<(&str, Rational64, &str, bool)> = totals.iter()
+            .map(|t| (t.generic_name(), t.amount(), t.unit(), t.exceeded()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("Caffeine", Rational64::from_integer(150), "mg", false),
+            ("Colecalciferol", Rational64::from_integer(1000), "IU", false),
+            ("Paracetamol", Rational64::from_integer(4500), "mg", true),
+        ]);
+        assert_eq!(totals[2].sources().len(), 2);
+        assert!(totals[2].warning().unwrap().starts_with("Paracetamol 4500 mg per day exceeds the maximum of 4000 mg"));
+        assert_eq!(totals[0].warning(), None);
+    }
+}
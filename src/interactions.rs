@@ -0,0 +1,244 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG;
+use crate::ingredients::ingredient_key;
+use crate::model::Drug;
+
+
+/// How serious an interaction between two active ingredients is.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Severity {
+    Minor,
+    Moderate,
+    Major,
+    Contraindicated,
+}
+
+/// A known interaction between two active ingredients, as listed in the interaction table.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct Interaction {
+    ingredient_a: String,
+    ingredient_b: String,
+    severity: Severity,
+    #[serde(default)] description: String,
+}
+
+/// An interaction between the active ingredients of drugs that are currently taken.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct FoundInteraction {
+    ingredient_a: String,
+    /// The trade names of the drugs containing `ingredient_a`.
+    drugs_a: Vec<String>,
+    ingredient_b: String,
+    /// The trade names of the drugs containing `ingredient_b`.
+    drugs_b: Vec<String>,
+    severity: Severity,
+    description: String,
+}
+
+
+impl Severity {
+    pub const ALL: [Severity; 4] = [Self::Minor, Self::Moderate, Self::Major, Self::Contraindicated];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Minor => "minor",
+            Self::Moderate => "moderate",
+            Self::Major => "major",
+            Self::Contraindicated => "contraindicated",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_lowercase();
+        Self::ALL.into_iter()
+            .find(|severity| severity.as_str() == value)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FoundInteraction {
+    pub fn ingredient_a(&self) -> &str { &self.ingredient_a }
+    pub fn drugs_a(&self) -> String { self.drugs_a.join(", ") }
+    pub fn ingredient_b(&self) -> &str { &self.ingredient_b }
+    pub fn drugs_b(&self) -> String { self.drugs_b.join(", ") }
+    pub fn severity(&self) -> Severity { self.severity }
+    pub fn description(&self) -> &str { &self.description }
+}
+
+
+/// Parses an interaction table in CSV format.
+///
+/// Each line consists of the two generic names, the severity and an optional description, which
+/// may itself contain commas. Empty lines and lines starting with `#` are ignored.
+pub(crate) fn parse_csv(text: &str) -> Result<Vec<Interaction>, String> {
+    let mut interactions = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(4, ',')
+            .map(|f| f.trim())
+            .collect();
+        if fields.len() < 3 || fields[0].is_empty() || fields[1].is_empty() {
+            return Err(format!("line {}: expected two generic names and a severity", i + 1));
+        }
+        let severity = Severity::parse(fields[2])
+            .ok_or_else(|| format!("line {}: unknown severity {:?}", i + 1, fields[2]))?;
+        interactions.push(Interaction {
+            ingredient_a: fields[0].to_owned(),
+            ingredient_b: fields[1].to_owned(),
+            severity,
+            description: fields.get(3).copied().unwrap_or("").to_owned(),
+        });
+    }
+    Ok(interactions)
+}
+
+/// Loads an interaction table, in JSON format if the file name ends in `.json` and in CSV format
+/// (see [`parse_csv`]) otherwise.
+pub(crate) fn load_table(path: &Path) -> Result<Vec<Interaction>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read interaction table {:?}: {}", path, e))?;
+    let is_json = path.extension()
+        .map(|ext| ext.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    let parsed = if is_json {
+        serde_json::from_str(&text)
+            .map_err(|e| e.to_string())
+    } else {
+        parse_csv(&text)
+    };
+    parsed.map_err(|e| format!("failed to parse interaction table {:?}: {}", path, e))
+}
+
+/// Loads the interaction table given in the configuration, if any.
+///
+/// The table is read anew each time so that changes to it take effect immediately.
+pub(crate) async fn load_configured_table() -> Result<Vec<Interaction>, String> {
+    let interactions_path = {
+        let config_guard = CONFIG
+            .get().expect("CONFIG not set")
+            .read().await;
+        config_guard.interactions_path.clone()
+    };
+    match interactions_path {
+        Some(path) => load_table(Path::new(&path)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Finds the interactions in `table` between active ingredients of different shown drugs, the
+/// most severe first.
+pub(crate) fn check_interactions(data: &[Drug], table: &[Interaction]) -> Vec<FoundInteraction> {
+    let drugs_containing = |generic_name: &str| -> Vec<&Drug> {
+        let key = ingredient_key(generic_name);
+        data.iter()
+            .filter(|drug| drug.show())
+            .filter(|drug| drug.components().iter().any(|c| ingredient_key(c.generic_name()) == key))
+            .collect()
+    };
+
+    let mut found = Vec::new();
+    for interaction in table {
+        let drugs_a = drugs_containing(&interaction.ingredient_a);
+        let drugs_b = drugs_containing(&interaction.ingredient_b);
+        // combination products are assumed to be safe in themselves
+        let different_drugs = drugs_a.iter()
+            .any(|a| drugs_b.iter().any(|b| a.id() != b.id()));
+        if !different_drugs {
+            continue;
+        }
+        let trade_names = |drugs: Vec<&Drug>| drugs.iter()
+            .map(|drug| drug.trade_name().to_owned())
+            .collect();
+        found.push(FoundInteraction {
+            ingredient_a: interaction.ingredient_a.clone(),
+            drugs_a: trade_names(drugs_a),
+            ingredient_b: interaction.ingredient_b.clone(),
+            drugs_b: trade_names(drugs_b),
+            severity: interaction.severity,
+            description: interaction.description.clone(),
+        });
+    }
+    found.sort_by_key(|f| Reverse(f.severity));
+    found
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_traits::One;
+    use crate::model::DrugComponent;
+    use super::*;
+
+    fn drug(id: u64, generic_names: &[&str]) -> Drug {
+        let components = generic_names.iter()
+            .map(|gn| DrugComponent::new((*gn).to_owned(), One::one(), "mg".to_owned()))
+            .collect();
+        Drug::for_test(id, 10)
+            .with_components(components)
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let table = parse_csv(
+            "# ingredient_a,ingredient_b,severity,description\n\
+            \n\
+            warfarin, acetylsalicylic acid, Major, increased risk of bleeding, especially in the stomach\n\
+            ibuprofen,acetylsalicylic acid,moderate\n"
+        ).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].severity, Severity::Major);
+        assert_eq!(table[0].ingredient_b, "acetylsalicylic acid");
+        assert_eq!(table[0].description, "increased risk of bleeding, especially in the stomach");
+        assert_eq!(table[1].description, "");
+
+        assert!(parse_csv("warfarin,acetylsalicylic acid,dangerous").is_err());
+        assert!(parse_csv("warfarin,major").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let table: Vec<Interaction> = serde_json::from_str(
+            r#"[{"ingredient_a": "warfarin", "ingredient_b": "ibuprofen", "severity": "contraindicated"}]"#
+        ).unwrap();
+        assert_eq!(table[0].severity, Severity::Contraindicated);
+    }
+
+    #[test]
+    fn test_check_interactions() {
+        let table = parse_csv(
+            "Ibuprofen,paracetamol,minor\n\
+            warfarin,acetylsalicylic acid,major\n\
+            codeine,paracetamol,moderate\n"
+        ).unwrap();
+        let data = vec![
+            drug(1, &["acetylsalicylic acid"]),
+            drug(2, &["Warfarin"]),
+            drug(3, &["paracetamol", "codeine"]),
+            drug(4, &["ibuprofen"]),
+        ];
+        let found = check_interactions(&data, &table);
+        let summary: Vec<(Severity, &str, String)> = found.iter()
+            .map(|f| (f.severity(), f.ingredient_a(), f.drugs_b()))
+            .collect();
+        // paracetamol and codeine are only contained in the same drug
+        assert_eq!(summary, vec![
+            (Severity::Major, "warfarin", "drug 1".to_owned()),
+            (Severity::Minor, "Ibuprofen", "drug 3".to_owned()),
+        ]);
+    }
+}
@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use num_rational::Rational64;
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::model::{find_drug_by_id, Drug, Patient};
+use crate::schedule::DoseSlot;
+
+
+/// The number of days over which the doses of drugs taken as needed are averaged.
+pub(crate) const DOSE_AVERAGE_DAYS: i64 = 30;
+
+
+/// The kind of operation that caused a journal event.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum JournalAction {
+    Replenish,
+    ReplenishFromPrescription,
+    Reduce,
+    TakeDays,
+    TakeDose,
+    LogDose,
+    FillOrganizer,
+    DiscardExpired,
+    Transfer,
+    AddDrug,
+    EditDrug,
+    DeleteDrug,
+    Undo,
+    AutoConsume,
+}
+
+/// A change to the stock of a single drug, recorded in the journal.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct JournalEvent {
+    /// Number of the operation that caused this event; events caused by the same request (e.g.
+    /// one "take days" action) share the same operation number.
+    operation: u64,
+    timestamp: DateTime<Utc>,
+    drug_id: u64,
+    action: JournalAction,
+    delta: Rational64,
+    remaining: Rational64,
+    token_label: Option<String>,
+    /// For undo events, the number of the operation that has been reverted.
+    #[serde(default, skip_serializing_if = "Option::is_none")] undoes: Option<u64>,
+    /// For logged doses, which dose it was and whether it has been taken.
+    #[serde(default, skip_serializing_if = "Option::is_none")] dose: Option<DoseRecord>,
+}
+
+/// The events read from a journal file.
+struct JournalContents {
+    events: Vec<JournalEvent>,
+    /// The length of the part of the file consisting of complete lines.
+    complete_len: usize,
+    total_len: usize,
+}
+
+/// A change to the stock of a drug that has yet to be written to the journal.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct StockChange {
+    pub drug_id: u64,
+    pub delta: Rational64,
+    pub remaining: Rational64,
+    pub dose: Option<DoseRecord>,
+}
+
+/// Whether a scheduled dose has been taken.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DoseStatus {
+    Taken,
+    /// Taken, but not at the scheduled time.
+    Late,
+    Skipped,
+}
+
+/// A scheduled dose of a drug as logged in the journal.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct DoseRecord {
+    pub date: NaiveDate,
+    pub slot: DoseSlot,
+    pub status: DoseStatus,
+}
+
+
+impl JournalAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Replenish => "replenish",
+            Self::ReplenishFromPrescription => "replenish-from-prescription",
+            Self::Reduce => "reduce",
+            Self::TakeDays => "take-days",
+            Self::TakeDose => "take-dose",
+            Self::LogDose => "log-dose",
+            Self::FillOrganizer => "fill-organizer",
+            Self::DiscardExpired => "discard-expired",
+            Self::Transfer => "transfer",
+            Self::AddDrug => "add-drug",
+            Self::EditDrug => "edit-drug",
+            Self::DeleteDrug => "delete-drug",
+            Self::Undo => "undo",
+            Self::AutoConsume => "auto-consume",
+        }
+    }
+
+    /// Whether operations of this kind can be undone by reverting their stock deltas.
+    pub fn is_undoable(&self) -> bool {
+        match self {
+            Self::Replenish|Self::ReplenishFromPrescription|Self::Reduce|Self::TakeDays|Self::TakeDose
+                |Self::LogDose|Self::FillOrganizer => true,
+            Self::DiscardExpired|Self::Transfer|Self::AddDrug|Self::EditDrug|Self::DeleteDrug|Self::Undo
+                |Self::AutoConsume => false,
+        }
+    }
+
+    /// Returns the action describing a manual stock adjustment by `delta`.
+    pub fn for_adjustment(delta: &Rational64) -> Self {
+        if *delta.numer() < 0 {
+            Self::Reduce
+        } else {
+            Self::Replenish
+        }
+    }
+}
+
+impl JournalEvent {
+    pub fn operation(&self) -> u64 { self.operation }
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn action(&self) -> JournalAction { self.action }
+    pub fn delta(&self) -> Rational64 { self.delta }
+    pub fn remaining(&self) -> Rational64 { self.remaining }
+    pub fn token_label(&self) -> Option<&str> { self.token_label.as_deref() }
+    pub fn undoes(&self) -> Option<u64> { self.undoes }
+    pub fn dose(&self) -> Option<&DoseRecord> { self.dose.as_ref() }
+
+    pub fn local_timestamp(&self) -> String {
+        self.timestamp
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+}
+
+impl StockChange {
+    pub fn new(drug_id: u64, delta: Rational64, remaining: Rational64) -> Self {
+        Self {
+            drug_id,
+            delta,
+            remaining,
+            dose: None,
+        }
+    }
+
+    /// Attaches the logged dose that caused this change.
+    pub fn with_dose(mut self, dose: DoseRecord) -> Self {
+        self.dose = Some(dose);
+        self
+    }
+}
+
+impl DoseStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Taken => "taken",
+            Self::Late => "late",
+            Self::Skipped => "skipped",
+        }
+    }
+
+    pub fn from_form_value(value: &str) -> Option<Self> {
+        match value {
+            "taken" => Some(Self::Taken),
+            "late" => Some(Self::Late),
+            "skipped" => Some(Self::Skipped),
+            _ => None,
+        }
+    }
+
+    /// Whether the dose has been taken from the stock.
+    pub fn consumes(&self) -> bool {
+        match self {
+            Self::Taken|Self::Late => true,
+            Self::Skipped => false,
+        }
+    }
+}
+
+
+/// Parses the lines of a journal.
+///
+/// Every event is written as a line of its own, terminated by a newline; a last line without a
+/// newline is left over from an append that has been interrupted and is therefore left out.
+fn parse_events(text: &str) -> serde_json::Result<JournalContents> {
+    let mut events = Vec::new();
+    let mut complete_len = 0;
+    for line in text.split_inclusive('\n') {
+        if !line.ends_with('\n') {
+            break;
+        }
+        complete_len += line.len();
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(line)?);
+    }
+    Ok(JournalContents {
+        events,
+        complete_len,
+        total_len: text.len(),
+    })
+}
+
+fn read_events(path: &Path) -> io::Result<JournalContents> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let contents = parse_events(&text)?;
+    if contents.complete_len < contents.total_len {
+        warn!("ignoring the incomplete last line of journal {:?}", path);
+    }
+    Ok(contents)
+}
+
+/// Loads all events from the journal of the given patient, oldest first.
+pub(crate) async fn load_journal(patient: &Patient) -> Option<Vec<JournalEvent>> {
+    let path = patient.journal_path();
+    match read_events(&path) {
+        Ok(contents) => Some(contents.events),
+        Err(e) => {
+            error!("failed to load journal {:?}: {}", path, e);
+            None
+        },
+    }
+}
+
+/// Returns the highest drug ID referred to by the journal, including the IDs of deleted drugs.
+pub(crate) fn highest_drug_id(events: &[JournalEvent]) -> u64 {
+    events.iter()
+        .map(|ev| ev.drug_id)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the numbers of all operations that can still be undone.
+pub(crate) fn undoable_operations(events: &[JournalEvent]) -> HashSet<u64> {
+    let undone: HashSet<u64> = events.iter()
+        .filter_map(|ev| ev.undoes)
+        .collect();
+    events.iter()
+        .filter(|ev| ev.action.is_undoable())
+        .map(|ev| ev.operation)
+        .filter(|op| !undone.contains(op))
+        .collect()
+}
+
+/// Returns the most recent status of each logged dose by drug ID, date and time of day, leaving out
+/// doses whose logging has been undone.
+pub(crate) fn logged_doses(events: &[JournalEvent]) -> HashMap<(u64, NaiveDate, DoseSlot), DoseStatus> {
+    let undone: HashSet<u64> = events.iter()
+        .filter_map(|ev| ev.undoes)
+        .collect();
+    let mut doses = HashMap::new();
+    for event in events {
+        if undone.contains(&event.operation) {
+            continue;
+        }
+        if let Some(dose) = &event.dose {
+            doses.insert((event.drug_id, dose.date, dose.slot), dose.status);
+        }
+    }
+    doses
+}
+
+/// Returns the average amount per day of each drug logged as an individual dose within the `days`
+/// days before `now`, leaving out doses that have been undone.
+pub(crate) fn average_daily_doses(events: &[JournalEvent], now: DateTime<Utc>, days: i64) -> HashMap<u64, Rational64> {
+    let since = now - Duration::days(days);
+    let undone: HashSet<u64> = events.iter()
+        .filter_map(|ev| ev.undoes)
+        .collect();
+    let mut totals: HashMap<u64, Rational64> = HashMap::new();
+    for event in events {
+        if event.action != JournalAction::TakeDose || event.timestamp < since || undone.contains(&event.operation) {
+            continue;
+        }
+        *totals.entry(event.drug_id).or_insert_with(Zero::zero) -= event.delta;
+    }
+    for total in totals.values_mut() {
+        *total /= Rational64::from_integer(days);
+    }
+    totals
+}
+
+/// Returns the total amount of each drug logged as an individual dose on `date` (local time),
+/// leaving out doses that have been undone.
+pub(crate) fn doses_taken_on(events: &[JournalEvent], date: NaiveDate) -> HashMap<u64, Rational64> {
+    let undone: HashSet<u64> = events.iter()
+        .filter_map(|ev| ev.undoes)
+        .collect();
+    let mut totals: HashMap<u64, Rational64> = HashMap::new();
+    for event in events {
+        if event.action != JournalAction::TakeDose || undone.contains(&event.operation) {
+            continue;
+        }
+        if event.timestamp.with_timezone(&Local).date_naive() != date {
+            continue;
+        }
+        *totals.entry(event.drug_id).or_insert_with(Zero::zero) -= event.delta;
+    }
+    totals
+}
+
+/// Reverts the stock changes of the given operation (or, if `None`, the most recent operation that
+/// can be undone) on `data`.
+///
+/// Returns the number of the reverted operation and the changes applied to revert it.
+pub(crate) fn undo(data: &mut [Drug], events: &[JournalEvent], operation: Option<u64>) -> Result<(u64, Vec<StockChange>), &'static str> {
+    let undoable = undoable_operations(events);
+    let operation = match operation {
+        Some(op) => {
+            if !undoable.contains(&op) {
+                return Err("operation does not exist, cannot be undone or has already been undone");
+            }
+            op
+        },
+        None => *undoable.iter()
+            .max()
+            .ok_or("there is nothing to undo")?,
+    };
+
+    let mut changes = Vec::new();
+    for event in events.iter().filter(|ev| ev.operation == operation) {
+        // skip drugs that have been deleted in the meantime
+        let index = match find_drug_by_id(data, event.drug_id) {
+            Some(i) => i,
+            None => continue,
+        };
+        // take back exactly the stock that was added; reductions come back as stock of unknown expiry
+        let delta = if event.delta > Zero::zero() {
+            data[index].remove_newest(&event.delta)
+        } else {
+            data[index].adjust(&-event.delta)
+        };
+        if event.action == JournalAction::ReplenishFromPrescription {
+            data[index].restore_prescription_refill();
+        }
+        changes.push(StockChange::new(event.drug_id, delta, data[index].remaining()));
+    }
+    if changes.is_empty() {
+        return Err("all drugs affected by the operation have been deleted");
+    }
+    Ok((operation, changes))
+}
+
+/// Appends the given changes to the journal of the given patient as a single operation.
+///
+/// Must be called while holding the data lock, otherwise operation numbers may be handed out
+/// twice.
+pub(crate) async fn record(patient: &Patient, action: JournalAction, token_label: Option<&str>, changes: &[StockChange]) -> bool {
+    append(patient, action, token_label, None, changes).await
+}
+
+/// Appends the changes reverting the given operation to the journal of the given patient.
+///
+/// Must be called while holding the data lock.
+pub(crate) async fn record_undo(patient: &Patient, undone_operation: u64, token_label: Option<&str>, changes: &[StockChange]) -> bool {
+    append(patient, JournalAction::Undo, token_label, Some(undone_operation), changes).await
+}
+
+async fn append(
+    patient: &Patient,
+    action: JournalAction,
+    token_label: Option<&str>,
+    undoes: Option<u64>,
+    changes: &[StockChange],
+) -> bool {
+    if changes.is_empty() {
+        return true;
+    }
+    let path = patient.journal_path();
+    let existing = match read_events(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("failed to load journal {:?}: {}", path, e);
+            return false;
+        },
+    };
+    let operation = existing.events.iter()
+        .map(|ev| ev.operation)
+        .max()
+        .unwrap_or(0) + 1;
+    let timestamp = Utc::now();
+
+    let mut lines = String::new();
+    for change in changes {
+        let event = JournalEvent {
+            operation,
+            timestamp,
+            drug_id: change.drug_id,
+            action,
+            delta: change.delta,
+            remaining: change.remaining,
+            token_label: token_label.map(|tl| tl.to_owned()),
+            undoes,
+            dose: change.dose,
+        };
+        let line = serde_json::to_string(&event)
+            .expect("failed to serialize journal event");
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| {
+            if existing.complete_len < existing.total_len {
+                // drop the remainder of an interrupted append so that it does not merge with the
+                // first new line
+                file.set_len(existing.complete_len as u64)?;
+            }
+            file.write_all(lines.as_bytes())?;
+            file.sync_data()
+        });
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            error!("failed to append to journal {:?}: {}", path, e);
+            false
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_rational::Rational64;
+    use crate::model::next_drug_id;
+    use super::*;
+
+    fn event(operation: u64, drug_id: u64, action: &str, delta: i64, undoes: Option<u64>) -> JournalEvent {
+        let undoes_json = match undoes {
+            Some(u) => format!(",\"undoes\":{}", u),
+            None => String::new(),
+        };
+        let json = format!(
+            "{{\"operation\":{},\"timestamp\":\"2023-01-01T00:00:00Z\",\"drug_id\":{},\"action\":{:?},\"delta\":[{},1],\"remaining\":[0,1],\"token_label\":null{}}}",
+            operation, drug_id, action, delta, undoes_json,
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_undo() {
+        let events = vec![
+            event(1, 1, "replenish", 30, None),
+            event(2, 1, "take-days", -7, None),
+            event(2, 2, "take-days", -14, None),
+            event(3, 1, "edit-drug", 0, None),
+            event(4, 1, "undo", -30, Some(1)),
+        ];
+        assert_eq!(undoable_operations(&events), [2].into_iter().collect());
+
+        let mut data = vec![Drug::for_test(1, 10), Drug::for_test(2, 5)];
+        let (operation, changes) = undo(&mut data, &events, None).unwrap();
+        assert_eq!(operation, 2);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(data[0].remaining(), Rational64::from_integer(17));
+        assert_eq!(data[1].remaining(), Rational64::from_integer(19));
+
+        assert!(undo(&mut data, &events, Some(1)).is_err());
+        assert!(undo(&mut data, &events, Some(3)).is_err());
+    }
+
+    #[test]
+    fn test_deleted_id_not_reused() {
+        let mut events = vec![
+            event(1, 1, "add-drug", 10, None),
+            event(2, 2, "add-drug", 0, None),
+            event(3, 2, "replenish", 30, None),
+            event(4, 2, "delete-drug", -30, None),
+        ];
+        let mut data = vec![Drug::for_test(1, 10)];
+        let new_id = next_drug_id(&data, highest_drug_id(&events));
+        assert_eq!(new_id, 3);
+        data.push(Drug::for_test(new_id, 5));
+        events.push(event(5, new_id, "add-drug", 5, None));
+
+        // the replenishment belonged to the deleted drug and must not affect the new one
+        assert!(undo(&mut data, &events, None).is_err());
+        assert_eq!(data[1].remaining(), Rational64::from_integer(5));
+    }
+
+    #[test]
+    fn test_parse_incomplete_line() {
+        let first = serde_json::to_string(&event(1, 1, "replenish", 30, None)).unwrap();
+        let second = serde_json::to_string(&event(2, 1, "take-days", -7, None)).unwrap();
+        let text = format!("{}\n\n{}\n{}", first, second, &second[..20]);
+        let contents = parse_events(&text).unwrap();
+        assert_eq!(contents.events.len(), 2);
+        assert_eq!(contents.complete_len, first.len() + second.len() + 3);
+        assert_eq!(contents.total_len, text.len());
+
+        // damage anywhere else is not the result of an interrupted append
+        let damaged = format!("{}\n{}\n", &first[..20], second);
+        assert!(parse_events(&damaged).is_err());
+    }
+
+    #[test]
+    fn test_average_daily_doses() {
+        let events = vec![
+            event(1, 1, "take-dose", -2, None),
+            event(2, 1, "take-dose", -1, None),
+            event(3, 2, "take-dose", -3, None),
+            event(4, 1, "replenish", 10, None),
+            event(5, 2, "undo", 3, Some(3)),
+        ];
+        let now: DateTime<Utc> = "2023-01-05T00:00:00Z".parse().unwrap();
+        let averages = average_daily_doses(&events, now, 30);
+        assert_eq!(averages.len(), 1);
+        assert_eq!(averages[&1], Rational64::new(1, 10));
+
+        let later: DateTime<Utc> = "2023-03-01T00:00:00Z".parse().unwrap();
+        assert!(average_daily_doses(&events, later, 30).is_empty());
+    }
+}
@@ -1,67 +1,129 @@
+mod actions;
+mod adherence;
+mod api;
+mod drug_form;
+mod expired;
 mod filters;
+mod ingredients;
+mod interactions;
+mod journal;
 mod model;
+mod organizer;
+mod planner;
+mod schedule;
+mod travel;
+mod units;
 mod util;
 
 
 use std::borrow::Cow;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::{Infallible, TryInto};
 use std::env;
 use std::ffi::OsString;
 use std::fs::{self, File};
 use std::io::{self, Read};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use askama::Template;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, Utc};
+#[allow(clippy::single_component_path_imports)]
 use form_urlencoded;
 use http::header::IF_MODIFIED_SINCE;
-use hyper::{Body, Method, Request, Response, Server};
+use hyper::{Body, Method, Request, Response, Server, Uri};
 use hyper::service::{make_service_fn, service_fn};
 use num_rational::Rational64;
 use num_traits::Zero;
 use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
+#[allow(clippy::single_component_path_imports)]
 use serde_json;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+#[allow(clippy::single_component_path_imports)]
 use toml;
 use tracing::{debug, error};
 use url::Url;
 
-use crate::model::{Config, DailyPills, Drug, DrugToDisplay};
-use crate::util::parse_decimal;
+use crate::actions::{Modification, ModifyError, Replenishment};
+use crate::drug_form::{get_optional_date, handle_get_drug_form, parse_drug_form};
+use crate::interactions::FoundInteraction;
+use crate::journal::{DoseRecord, DoseStatus, JournalAction, JournalEvent, StockChange};
+use crate::model::{
+    assign_missing_ids, find_drug_by_id, known_locations, lacks_ids, next_drug_id, Config, DailyPills, Drug,
+    DrugToDisplay, Patient, QuantityUnit, ReplenishmentThresholds, PRESCRIPTION_WARNING_DAYS,
+};
+use crate::schedule::DoseSlot;
+use crate::util::{parse_amount, write_file_atomically};
 
 
+#[allow(clippy::redundant_static_lifetimes)]
 const HTTP_TIMESTAMP_FORMAT: &'static str = "%a, %d %b %Y %H:%M:%S GMT";
 
 
 static CONFIG: OnceCell<RwLock<Config>> = OnceCell::new();
+/// Held across each load-modify-store cycle of the data file to prevent lost updates.
+static DATA_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 static IMAGE_PATH_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(
     "^/images/(?P<filename>[A-Za-z0-9-_]+[.][A-Za-z0-9]+)$"
 ).expect("failed to compile regex"));
 
 
+/// The identity behind an authenticated request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Auth {
+    pub token_label: Option<String>,
+    /// The patient whose data the request concerns.
+    pub patient: Patient,
+    /// All patients whose data the token grants access to.
+    pub patients: Vec<Patient>,
+}
+
+
 #[derive(Template)]
 #[template(path = "main.html", escape = "none")]
 struct MainTemplate<'a, 'b> {
+    pub token: &'a str,
+    pub patient: &'a str,
     pub profile_columns: &'a Vec<String>,
     pub drugs_to_display: &'b Vec<DrugToDisplay>,
     pub min_weeks_per_prescription: Option<i64>,
     pub pill_counts: DailyPills,
     pub hide_ui: bool,
+    pub can_undo: bool,
+    pub locations: Vec<String>,
+    pub ingredient_warnings: Vec<String>,
+    /// The names of all patients the token grants access to.
+    pub patient_names: Vec<&'a str>,
+    pub interactions: Vec<FoundInteraction>,
+    pub interaction_error: Option<String>,
 }
 
+#[derive(Template)]
+#[template(path = "history.html", escape = "none")]
+struct HistoryTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub drug: &'a Drug,
+    pub events: Vec<JournalEvent>,
+    pub undoable_operations: HashSet<u64>,
+}
 
-async fn load_data() -> Option<Vec<Drug>> {
-    let data_path = {
-        let config_guard = CONFIG
-            .get().expect("config is not set")
-            .read().await;
-        config_guard.data_path.clone()
-    };
-    let reader = match File::open(&data_path) {
+impl<'a, 'b> MainTemplate<'a, 'b> {
+    fn is_current_patient(&self, name: &str) -> bool {
+        self.patient == name
+    }
+}
+
+impl<'a> HistoryTemplate<'a> {
+    fn can_undo(&self, event: &JournalEvent) -> bool {
+        self.undoable_operations.contains(&event.operation())
+    }
+}
+
+
+async fn load_data(patient: &Patient) -> Option<Vec<Drug>> {
+    let reader = match File::open(&patient.data_path) {
         Ok(r) => r,
         Err(e) => {
             error!("failed to open file: {}", e);
@@ -69,31 +131,34 @@ async fn load_data() -> Option<Vec<Drug>> {
         },
     };
 
-    match serde_json::from_reader(reader) {
-        Ok(vd) => Some(vd),
+    let mut data: Vec<Drug> = match serde_json::from_reader(reader) {
+        Ok(vd) => vd,
         Err(e) => {
             error!("failed to load data: {}", e);
-            None
+            return None;
         },
+    };
+
+    // IDs are assigned deterministically, so they remain the same across loads even before the
+    // next modification persists them; the journal knows the IDs of deleted drugs, which must not
+    // be reused
+    if lacks_ids(&data) {
+        let events = journal::load_journal(patient).await?;
+        assign_missing_ids(&mut data, journal::highest_drug_id(&events));
+    }
+    for drug in &mut data {
+        drug.normalize_batches();
     }
-}
 
-async fn store_data(data: &[Drug]) -> bool {
-    let data_path = {
-        let config_guard = CONFIG
-            .get().expect("config is not set")
-            .read().await;
-        config_guard.data_path.clone()
-    };
-    let writer = match File::create(&data_path) {
-        Ok(r) => r,
-        Err(e) => {
-            error!("failed to open file: {}", e);
-            return false;
-        },
-    };
+    Some(data)
+}
 
-    match serde_json::to_writer_pretty(writer, data) {
+async fn store_data(patient: &Patient, data: &[Drug]) -> bool {
+    let result = write_file_atomically(Path::new(&patient.data_path), |writer| {
+        serde_json::to_writer_pretty(&mut *writer, data)?;
+        Ok(())
+    });
+    match result {
         Ok(()) => true,
         Err(e) => {
             error!("failed to store data: {}", e);
@@ -112,6 +177,7 @@ fn respond_500() -> Result<Response<Body>, Infallible> {
     Ok(resp)
 }
 
+#[allow(clippy::needless_return)]
 fn respond_304() -> Result<Response<Body>, Infallible> {
     let resp_res = Response::builder()
         .status(304)
@@ -125,6 +191,7 @@ fn respond_304() -> Result<Response<Body>, Infallible> {
     }
 }
 
+#[allow(clippy::needless_return)]
 fn respond_400(message: &str) -> Result<Response<Body>, Infallible> {
     let resp_body = Body::from(format!("400 Bad Request: {}", message));
     let resp_res = Response::builder()
@@ -140,8 +207,9 @@ fn respond_400(message: &str) -> Result<Response<Body>, Infallible> {
     }
 }
 
-fn respond_403() -> Result<Response<Body>, Infallible> {
-    let resp_body = Body::from("403 Forbidden; token missing or invalid");
+#[allow(clippy::needless_return)]
+fn respond_403(message: &str) -> Result<Response<Body>, Infallible> {
+    let resp_body = Body::from(format!("403 Forbidden; {}", message));
     let resp_res = Response::builder()
         .status(403)
         .header("Content-Type", "text/plain; charset=utf-8")
@@ -155,6 +223,7 @@ fn respond_403() -> Result<Response<Body>, Infallible> {
     }
 }
 
+#[allow(clippy::needless_return)]
 fn respond_404() -> Result<Response<Body>, Infallible> {
     let resp_body = Body::from("404 Not Found; where the h*ck is it?");
     let resp_res = Response::builder()
@@ -170,6 +239,7 @@ fn respond_404() -> Result<Response<Body>, Infallible> {
     }
 }
 
+#[allow(clippy::needless_return)]
 fn respond_405(allowed: &str) -> Result<Response<Body>, Infallible> {
     let resp_body = Body::from(format!("405 Wrong Method; try one of: {}", allowed));
     let resp_res = Response::builder()
@@ -186,8 +256,171 @@ fn respond_405(allowed: &str) -> Result<Response<Body>, Infallible> {
     }
 }
 
-async fn handle_get(request: Request<Body>) -> Result<Response<Body>, Infallible> {
-    let data = match load_data().await {
+/// Deducts the consumption of all automatically tracked drugs up to `now` and hides drugs whose
+/// dosage plan has finished.
+///
+/// Must be applied, with the data lock held, before any other modification so that stock added or
+/// removed by the modification is not subject to consumption from before it happened.
+fn catch_up_consumption(data: &mut [Drug], now: DateTime<Utc>) -> Vec<StockChange> {
+    let today = now.with_timezone(&Local).date_naive();
+    let mut changes = Vec::new();
+    for drug in data {
+        drug.hide_if_finished(today);
+        let delta = drug.consume_until(now);
+        if !delta.is_zero() {
+            changes.push(StockChange::new(drug.id(), delta, drug.remaining()));
+        }
+    }
+    changes
+}
+
+async fn persist_consumption_periodically(period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+
+        let patients = {
+            let config_guard = CONFIG
+                .get().expect("config is not set")
+                .read().await;
+            config_guard.all_patients()
+        };
+        let _data_guard = DATA_LOCK.lock().await;
+        for patient in &patients {
+            let mut data = match load_data(patient).await {
+                None => continue,
+                Some(d) => d,
+            };
+            let loaded_data = data.clone();
+            let changes = catch_up_consumption(&mut data, Utc::now());
+            if changes.is_empty() {
+                continue;
+            }
+            debug!("persisting automatic consumption of {} drugs of {:?}", changes.len(), patient.name);
+            if store_data(patient, &data).await && !journal::record(patient, JournalAction::AutoConsume, None, &changes).await {
+                // try again next time rather than leave the consumption unrecorded
+                store_data(patient, &loaded_data).await;
+            }
+        }
+    }
+}
+
+async fn replenishment_thresholds() -> ReplenishmentThresholds {
+    let config_guard = CONFIG
+        .get().expect("CONFIG not set")
+        .read().await;
+    config_guard.replenishment
+}
+
+/// Prepares the drugs for display as of `now`.
+///
+/// The consumption of drugs taken as needed is forecast from `average_doses`, the recent average
+/// amount per day of each drug (see [`journal::average_daily_doses`]). `thresholds` are the
+/// configured replenishment thresholds.
+fn drugs_to_display(
+    data: &[Drug],
+    now: DateTime<Utc>,
+    average_doses: &HashMap<u64, Rational64>,
+    thresholds: &ReplenishmentThresholds,
+) -> Vec<DrugToDisplay> {
+    let today = now.with_timezone(&Local).date_naive();
+    data.iter()
+        .enumerate()
+        .map(|(i, d)| {
+            // show the stock as it should be by now
+            let mut d = d.clone();
+            d.consume_until(now);
+            d.hide_if_finished(today);
+
+            let average_dose_day = if d.as_needed() {
+                Some(average_doses.get(&d.id()).copied().unwrap_or_else(Zero::zero))
+            } else {
+                None
+            };
+
+            // how long will it last?
+            let dosage_day = average_dose_day.unwrap_or_else(|| d.remaining_dosage_day(today));
+            let total_dosage_week = dosage_day * Rational64::new(7, 1);
+            let days_of_stock: Option<Rational64> = if !d.dosage_plan().is_empty() {
+                // follow the plan day by day since the dosage changes
+                d.planned_days_of_stock(today)
+                    .map(Rational64::from_integer)
+            } else if *dosage_day.numer() > 0 {
+                Some(d.remaining() / dosage_day)
+            } else {
+                None
+            };
+            let full_days = days_of_stock.map(|days| days.floor().to_integer());
+            let full_weeks = full_days.map(|days| days / 7);
+
+            // how many weeks does a full prescription last?
+            let full_weeks_per_prescription = if *total_dosage_week.numer() > 0 {
+                let weeks_per_prescription = d.units_per_prescription() / total_dosage_week;
+                Some(weeks_per_prescription.numer() / weeks_per_prescription.denom())
+            } else {
+                None
+            };
+
+            let dosage = d.scheduled_dosage(today);
+            let mut drug_thresholds = thresholds.overridden_by(&d.replenishment());
+
+            // the stock runs out on the first day it does not fully cover
+            let run_out_date = full_days
+                .and_then(|days| today.checked_add_signed(Duration::days(days)));
+            let refill_by = |lead_time_days: i64| run_out_date
+                .and_then(|date| date.checked_sub_signed(Duration::days(lead_time_days)));
+
+            // a trip to the pharmacy does not suffice if the prescription cannot be filled anymore
+            let needs_doctor_visit = match (d.prescription(), refill_by(drug_thresholds.lead_time_days)) {
+                (Some(prescription), Some(pharmacy_date)) => !prescription.can_refill(pharmacy_date.max(today)),
+                _ => false,
+            };
+            if needs_doctor_visit {
+                drug_thresholds.lead_time_days += drug_thresholds.doctor_visit_days;
+            }
+            let refill_by_date = refill_by(drug_thresholds.lead_time_days);
+            let prescription_warnings = d.prescription()
+                .map(|p| p.warnings(today, PRESCRIPTION_WARNING_DAYS))
+                .unwrap_or_default();
+            let expiry_warnings = d.expiry_warnings(today, dosage_day);
+
+            DrugToDisplay::new(
+                i, d, full_days, full_weeks, full_weeks_per_prescription, dosage, average_dose_day,
+                drug_thresholds, run_out_date, refill_by_date, needs_doctor_visit, prescription_warnings,
+                expiry_warnings,
+            )
+        })
+        .collect()
+}
+
+/// Loads what is required from the patient's journal and prepares the drugs for display as of
+/// `now`.
+async fn load_drugs_to_display(patient: &Patient, data: &[Drug], now: DateTime<Utc>) -> Option<Vec<DrugToDisplay>> {
+    let journal = journal::load_journal(patient).await?;
+    let average_doses = journal::average_daily_doses(&journal, now, journal::DOSE_AVERAGE_DAYS);
+    let thresholds = replenishment_thresholds().await;
+    Some(drugs_to_display(data, now, &average_doses, &thresholds))
+}
+
+/// Keeps only the drugs shown on the main page.
+fn drugs_to_show(drugs: Vec<DrugToDisplay>) -> Vec<DrugToDisplay> {
+    drugs.into_iter()
+        .filter(|dtd| dtd.drug().show())
+        .collect()
+}
+
+fn min_weeks_per_prescription(data_to_show: &[DrugToDisplay]) -> Option<i64> {
+    data_to_show.iter()
+        .filter(|dtd| dtd.drug().show())
+        .filter(|dtd| dtd.drug().in_replenishment_cycle())
+        .filter(|dtd| !dtd.drug().as_needed())
+        .filter_map(|dtd| dtd.weeks_per_prescription())
+        .min()
+}
+
+#[allow(clippy::map_clone, clippy::needless_return, clippy::unnecessary_lazy_evaluations)]
+async fn handle_get(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let data = match load_data(&auth.patient).await {
         None => return respond_500(),
         Some(d) => d,
     };
@@ -216,7 +449,7 @@ async fn handle_get(request: Request<Body>) -> Result<Response<Body>, Infallible
             .unwrap_or_else(||
                 [
                     "obverse-photo", "reverse-photo", "trade-name", "components", "description",
-                    "remaining", "prescription", "dosage", "replenish",
+                    "remaining", "run-out", "prescription", "dosage", "replenish", "edit",
                 ]
                     .iter()
                     .map(|s| (*s).to_owned())
@@ -224,35 +457,17 @@ async fn handle_get(request: Request<Body>) -> Result<Response<Body>, Infallible
             )
     };
 
-    let data_to_show: Vec<DrugToDisplay> = data.iter()
-        .enumerate()
-        .map(|(i, d)| {
-            // how many weeks will it last?
-            let total_dosage_week = d.total_dosage_day() * Rational64::new(7, 1);
-            let full_weeks = if *total_dosage_week.numer() > 0 {
-                let doses_available = d.remaining() / total_dosage_week;
-                Some(doses_available.numer() / doses_available.denom())
-            } else {
-                None
-            };
-
-            // how many weeks does a full prescription last?
-            let full_weeks_per_prescription = if *total_dosage_week.numer() > 0 {
-                let weeks_per_prescription = d.units_per_prescription() / total_dosage_week;
-                Some(weeks_per_prescription.numer() / weeks_per_prescription.denom())
-            } else {
-                None
-            };
-
-            DrugToDisplay::new(i, d.clone(), full_weeks, full_weeks_per_prescription)
-        })
-        .filter(|dtd| dtd.drug().show())
-        .collect();
+    let journal = match journal::load_journal(&auth.patient).await {
+        None => return respond_500(),
+        Some(j) => j,
+    };
+    let can_undo = !journal::undoable_operations(&journal).is_empty();
 
-    let min_weeks_per_prescription = data_to_show.iter()
-        .filter(|dtd| dtd.drug().in_replenishment_cycle())
-        .filter_map(|dtd| dtd.weeks_per_prescription())
-        .min();
+    let now = Utc::now();
+    let average_doses = journal::average_daily_doses(&journal, now, journal::DOSE_AVERAGE_DAYS);
+    let thresholds = replenishment_thresholds().await;
+    let data_to_show = drugs_to_show(drugs_to_display(&data, now, &average_doses, &thresholds));
+    let min_weeks_per_prescription = min_weeks_per_prescription(&data_to_show);
 
     let mut pill_counts = DailyPills::new(
         0,
@@ -260,23 +475,56 @@ async fn handle_get(request: Request<Body>) -> Result<Response<Body>, Infallible
         0,
         0,
     );
+    let today = Local::now().date_naive();
     for drug in &data_to_show {
         if !drug.drug().is_pill() {
             continue;
         }
 
-        pill_counts.increase_morning(&drug.drug().dosage_morning());
-        pill_counts.increase_noon(&drug.drug().dosage_noon());
-        pill_counts.increase_evening(&drug.drug().dosage_evening());
-        pill_counts.increase_night(&drug.drug().dosage_night());
+        let dosage = drug.drug().dosage_on(today);
+        pill_counts.increase_morning(&dosage.morning);
+        pill_counts.increase_noon(&dosage.noon);
+        pill_counts.increase_evening(&dosage.evening);
+        pill_counts.increase_night(&dosage.night);
     }
 
+    let data_shown: Vec<Drug> = data_to_show.iter()
+        .map(|dtd| dtd.drug().clone())
+        .collect();
+    let ingredient_warnings: Vec<String> = ingredients::daily_totals(
+        &data_shown,
+        today,
+        &journal::doses_taken_on(&journal, today),
+        &ingredients::configured_limits().await,
+    )
+        .iter()
+        .filter_map(|total| total.warning())
+        .collect();
+    let (interactions, interaction_error) = match interactions::load_configured_table().await {
+        Ok(table) => (interactions::check_interactions(&data_shown, &table), None),
+        Err(e) => {
+            error!("{}", e);
+            (Vec::new(), Some(e))
+        },
+    };
+
+    let token = query_values
+        .get("token")
+        .unwrap_or(&Cow::Borrowed(""));
     let template = MainTemplate {
+        token: token.as_ref(),
+        patient: &auth.patient.name,
         drugs_to_display: &data_to_show,
         profile_columns: &actual_columns,
         min_weeks_per_prescription,
         pill_counts,
         hide_ui,
+        can_undo,
+        locations: known_locations(&data),
+        ingredient_warnings,
+        patient_names: auth.patients.iter().map(|p| p.name.as_str()).collect(),
+        interactions,
+        interaction_error,
     };
     let body_str = template.render()
         .expect("failed to render template");
@@ -294,7 +542,80 @@ async fn handle_get(request: Request<Body>) -> Result<Response<Body>, Infallible
     }
 }
 
-async fn handle_post(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+async fn handle_get_history(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    let index = match get_drug_index(&query_values, &data) {
+        Ok(i) => i,
+        Err(msg) => return respond_400(msg),
+    };
+    let drug = &data[index];
+
+    let journal = match journal::load_journal(&auth.patient).await {
+        None => return respond_500(),
+        Some(j) => j,
+    };
+    let undoable_operations = journal::undoable_operations(&journal);
+    let mut events: Vec<JournalEvent> = journal.into_iter()
+        .filter(|ev| ev.drug_id() == drug.id())
+        .collect();
+    events.reverse();
+
+    let template = HistoryTemplate {
+        token,
+        patient: &auth.patient.name,
+        drug,
+        events,
+        undoable_operations,
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+/// Finds the drug addressed by a form, preferring its ID and falling back to its index.
+fn get_drug_index(opts: &HashMap<String, String>, data: &[Drug]) -> Result<usize, &'static str> {
+    if let Some(id_str) = opts.get("drug-id") {
+        let id: u64 = id_str.parse()
+            .map_err(|_| "invalid value for \"drug-id\"")?;
+        return find_drug_by_id(data, id)
+            .ok_or("no drug found with this \"drug-id\"");
+    }
+
+    let index_str = opts.get("drug-index")
+        .ok_or("missing value for \"drug-id\"")?;
+    let index: usize = index_str.parse()
+        .map_err(|_| "invalid value for \"drug-index\"")?;
+    if index >= data.len() {
+        return Err("value for \"drug-index\" out of range");
+    }
+    Ok(index)
+}
+
+async fn handle_post(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
     let (head, body) = request.into_parts();
     let body_bytes = match hyper::body::to_bytes(body).await {
         Ok(bb) => bb,
@@ -314,74 +635,148 @@ async fn handle_post(request: Request<Body>) -> Result<Response<Body>, Infallibl
         None => return respond_400("missing value for \"do\""),
     };
 
-    let mut data = match load_data().await {
-        None => return respond_500(),
-        Some(d) => d,
-    };
-
-    match do_val.as_str() {
-        "replenish" => {
-            let index_str = match opts.get("drug-index") {
-                Some(s) => s,
-                None => return respond_400("missing value for \"drug-index\""),
-            };
-            let index: usize = match index_str.parse() {
-                Ok(i) => i,
-                Err(_) => return respond_400("invalid value for \"drug-index\""),
-            };
-            if index >= data.len() {
-                return respond_400("value for \"drug-index\" out of range");
-            }
-
-            let amount_str = match opts.get("amount") {
-                Some(s) => s,
-                None => return respond_400("missing value for \"amount\""),
-            };
-            let amount: Rational64 = match parse_decimal(amount_str) {
-                Ok(i) => i,
-                Err(_) => return respond_400("invalid value for \"amount\""),
-            };
-            match amount.cmp(&Zero::zero()) {
-                Ordering::Less => {
-                    let abs_amount = -amount;
-                    data[index].reduce(&abs_amount);
-                },
-                Ordering::Equal => {
-                    return respond_400("\"amount\" must not be 0");
-                },
-                Ordering::Greater => {
-                    data[index].replenish(&amount);
-                },
-            }
-        },
-        "take-days" => {
-            let days_str = match opts.get("days") {
-                Some(s) => s,
-                None => return respond_400("missing value for \"days\""),
-            };
-            let days: i64 = match days_str.parse() {
-                Ok(i) if i > 0 => i,
-                Ok(_) => return respond_400("invalid value for \"days\""),
-                Err(_) => return respond_400("invalid value for \"days\""),
-            };
-            for drug in &mut data {
-                let week_dose = drug.total_dosage_day() * Rational64::new(days, 1);
-                if week_dose > Zero::zero() {
-                    drug.reduce(&week_dose);
-                }
-            }
-        },
-        _other => {
-            return respond_400("unknown value for \"do\"");
-        },
+    let redirect_to_main_page = matches!(do_val.as_str(), "add-drug" | "edit-drug" | "delete-drug");
+    let result = actions::modify_data(&auth.patient, auth.token_label.as_deref(), |data, events, now| {
+        let today = now.with_timezone(&Local).date_naive();
+        match do_val.as_str() {
+            "replenish" => {
+                let index = get_drug_index(&opts, data)?;
+                let quantity = match opts.get("amount").map(|a| parse_amount(a)) {
+                    Some(Ok(q)) => q,
+                    Some(Err(e)) => return Err(format!("invalid value for \"amount\": {}", e).into()),
+                    None => return Err("missing value for \"amount\"".into()),
+                };
+                let unit = match opts.get("unit") {
+                    None => QuantityUnit::Units,
+                    Some(unit_str) => QuantityUnit::from_form_value(unit_str)
+                        .ok_or("invalid value for \"unit\"")?,
+                };
+                let replenishment = Replenishment {
+                    quantity,
+                    unit,
+                    from_prescription: opts.contains_key("from-prescription"),
+                    expiry: get_optional_date(&opts, "expiry")?,
+                    lot: opts.get("lot").cloned(),
+                };
+                actions::replenish(&mut data[index], replenishment, today)
+            },
+            "take-dose" => {
+                let index = get_drug_index(&opts, data)?;
+                let amount = match opts.get("amount").map(|a| parse_amount(a)) {
+                    None => None,
+                    Some(Ok(a)) => Some(a),
+                    Some(Err(e)) => return Err(format!("invalid value for \"amount\": {}", e).into()),
+                };
+                actions::take_dose(&mut data[index], amount)
+            },
+            "take-days" => {
+                let days: i64 = opts.get("days")
+                    .ok_or("missing value for \"days\"")?
+                    .parse()
+                    .map_err(|_| "invalid value for \"days\"")?;
+                actions::take_days(data, days, today)
+            },
+            "add-drug" => {
+                let mut drug = parse_drug_form(&opts, None, now)?;
+                drug.set_id(next_drug_id(data, journal::highest_drug_id(events)));
+                let changes = vec![StockChange::new(drug.id(), drug.remaining(), drug.remaining())];
+                data.push(drug);
+                Ok(Modification::new(JournalAction::AddDrug, changes))
+            },
+            "edit-drug" => {
+                let index = get_drug_index(&opts, data)?;
+                let mut drug = parse_drug_form(&opts, Some(&data[index]), now)?;
+                drug.set_id(data[index].id());
+                let delta = drug.carry_over_batches(&data[index]);
+                drug.carry_over_consumption_tracking(&data[index]);
+                let changes = vec![StockChange::new(drug.id(), delta, drug.remaining())];
+                data[index] = drug;
+                Ok(Modification::new(JournalAction::EditDrug, changes))
+            },
+            "transfer" => {
+                let index = get_drug_index(&opts, data)?;
+                let amount = match opts.get("amount").map(|a| parse_amount(a)) {
+                    Some(Ok(a)) => a,
+                    Some(Err(e)) => return Err(format!("invalid value for \"amount\": {}", e).into()),
+                    None => return Err("missing value for \"amount\"".into()),
+                };
+                let from = opts.get("from")
+                    .ok_or("missing value for \"from\"")?;
+                let to = opts.get("to")
+                    .ok_or("missing value for \"to\"")?;
+                actions::transfer(&mut data[index], amount, from, to)
+            },
+            "log-dose" => {
+                let index = get_drug_index(&opts, data)?;
+                let date = get_optional_date(&opts, "date")?
+                    .unwrap_or(today);
+                let slot = opts.get("slot")
+                    .and_then(|s| DoseSlot::from_form_value(s))
+                    .ok_or("invalid value for \"slot\"")?;
+                let status = opts.get("status")
+                    .and_then(|s| DoseStatus::from_form_value(s))
+                    .ok_or("invalid value for \"status\"")?;
+                actions::log_dose(&mut data[index], events, DoseRecord { date, slot, status }, today)
+            },
+            "fill-organizer" => {
+                let start = organizer::parse_start(opts.get("start").map(|s| s.as_str()), today)?;
+                Ok(actions::fill_organizer(data, start))
+            },
+            "pack-trip" => {
+                let trip = travel::Trip::from_values(&opts)?;
+                Ok(actions::pack_trip(data, today, &trip, opts.get("location").map(|l| l.as_str())))
+            },
+            "discard-expired" => {
+                Ok(actions::discard_expired(data, today))
+            },
+            "undo" => {
+                let operation = match opts.get("operation") {
+                    Some(op_str) => Some(
+                        op_str.parse()
+                            .map_err(|_| "invalid value for \"operation\"")?
+                    ),
+                    None => None,
+                };
+                actions::undo(data, events, operation)
+            },
+            "delete-drug" => {
+                let index = get_drug_index(&opts, data)?;
+                let drug = data.remove(index);
+                let changes = vec![StockChange::new(drug.id(), -drug.remaining(), Zero::zero())];
+                Ok(Modification::new(JournalAction::DeleteDrug, changes))
+            },
+            _other => Err("unknown value for \"do\"".into()),
+        }
+    }).await;
+    match result {
+        Ok(_) => {},
+        Err(ModifyError::Failed(_)) => return respond_500(),
+        Err(e) => return respond_400(e.message()),
     }
 
-    // write updated data
-    if !store_data(&data).await {
-        return respond_500();
+    if redirect_to_main_page {
+        // the form page is done with; go back to the overview
+        let token = get_token(&head.uri).unwrap_or_default();
+        let query: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", &token)
+            .append_pair("patient", &auth.patient.name)
+            .finish();
+        redirect(&format!("/?{}", query)).await
+    } else {
+        // redirect to myself
+        let path_and_query = match head.uri.path_and_query() {
+            Some(paq) => paq,
+            None => {
+                error!("failed to obtain path and query from request URL");
+                return respond_500();
+            },
+        };
+        redirect(path_and_query.as_str()).await
     }
+}
 
-    // redirect to myself
+#[allow(clippy::needless_return)]
+async fn redirect(path_and_query: &str) -> Result<Response<Body>, Infallible> {
     let base_url_string = {
         let config_guard = CONFIG
             .get().expect("config is not set")
@@ -396,27 +791,19 @@ async fn handle_post(request: Request<Body>) -> Result<Response<Body>, Infallibl
         },
     };
 
-    let path_and_query = match head.uri.path_and_query() {
-        Some(paq) => paq,
-        None => {
-            error!("failed to obtain path and query from request URL");
-            return respond_500();
-        },
-    };
-    let path_and_query_string = path_and_query.to_string();
-    let relative_path_and_query = path_and_query_string.trim_start_matches('/');
-    let my_url = match base_url.join(relative_path_and_query) {
+    let relative_path_and_query = path_and_query.trim_start_matches('/');
+    let target_url = match base_url.join(relative_path_and_query) {
         Ok(u) => u,
         Err(e) => {
             error!("failed to join path and query: {}", e);
             return respond_500();
         },
     };
-    debug!("my_url: {}", my_url);
+    debug!("redirecting to: {}", target_url);
 
     let response_res = Response::builder()
         .status(302)
-        .header("Location", my_url.to_string())
+        .header("Location", target_url.to_string())
         .body(Body::from(""));
     match response_res {
         Ok(r) => Ok(r),
@@ -427,6 +814,7 @@ async fn handle_post(request: Request<Body>) -> Result<Response<Body>, Infallibl
     }
 }
 
+#[allow(clippy::needless_return)]
 async fn handle_get_image(request: Request<Body>) -> Result<Response<Body>, Infallible> {
     let path_caps = match IMAGE_PATH_REGEX.captures(request.uri().path()) {
         Some(pc) => pc,
@@ -526,6 +914,52 @@ async fn handle_get_image(request: Request<Body>) -> Result<Response<Body>, Infa
     }
 }
 
+fn get_token(uri: &Uri) -> Option<String> {
+    let query_str = uri.query()?;
+    form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _v)| k == "token")
+        .map(|(_k, v)| v.into_owned())
+}
+
+fn get_patient_name(uri: &Uri) -> Option<String> {
+    let query_str = uri.query()?;
+    form_urlencoded::parse(query_str.as_bytes())
+        .find(|(k, _v)| k == "patient")
+        .map(|(_k, v)| v.into_owned())
+}
+
+/// Identifies the holder of the token and the patient whose data the request concerns: the one
+/// given as `patient` or, if none is given, the first one the token grants access to.
+async fn authenticate(request: &Request<Body>) -> Result<Auth, &'static str> {
+    let token_value = get_token(request.uri())
+        .ok_or("token missing or invalid")?;
+
+    let config_guard = CONFIG
+        .get().expect("config is not set")
+        .read().await;
+    let auth_token = config_guard.auth_tokens
+        .iter()
+        .find(|t| t.token() == token_value)
+        .ok_or("token missing or invalid")?;
+    let patients: Vec<Patient> = config_guard.all_patients()
+        .into_iter()
+        .filter(|p| auth_token.may_access(p))
+        .collect();
+    let patient = match get_patient_name(request.uri()) {
+        Some(name) => patients.iter()
+            .find(|p| p.name == name),
+        None => patients.first(),
+    }
+        .ok_or("patient unknown or not accessible with this token")?
+        .clone();
+
+    Ok(Auth {
+        token_label: auth_token.label().map(|l| l.to_owned()),
+        patient,
+        patients,
+    })
+}
+
 async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
     let uri_path = request.uri().path();
 
@@ -539,44 +973,44 @@ async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infall
     }
 
     // authentication starts here
-
-    // check for token
-    let query_str = match request.uri().query() {
-        None => return respond_403(),
-        Some(q) => q,
-    };
-    let query_kv: HashMap<String, String> = form_urlencoded::parse(query_str.as_bytes())
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
-    let token_value = match query_kv.get("token") {
-        None => return respond_403(),
-        Some(tv) => tv,
-    };
-
-    let token_matches = {
-        CONFIG
-            .get().expect("config is not set")
-            .read().await
-            .auth_tokens
-            .iter()
-            .any(|t| t == token_value)
+    let is_api = uri_path.starts_with(api::API_PREFIX);
+    let auth = match authenticate(&request).await {
+        Ok(a) => a,
+        Err(msg) => return if is_api {
+            api::respond_error(403, msg)
+        } else {
+            respond_403(msg)
+        },
     };
-    if !token_matches {
-        return respond_403();
-    }
 
     // authenticated-only endpoints beyond this line
 
+    if is_api {
+        return api::handle_api_request(request, &auth).await;
+    }
+
     if request.method() == Method::GET {
-        handle_get(request).await
+        match uri_path {
+            "/" => handle_get(request, &auth).await,
+            "/drug" => handle_get_drug_form(request, &auth).await,
+            "/history" => handle_get_history(request, &auth).await,
+            "/planner" => planner::handle_get_planner(request, &auth).await,
+            "/expired" => expired::handle_get_expired(request, &auth).await,
+            "/travel" => travel::handle_get_travel(request, &auth).await,
+            "/organizer" => organizer::handle_get_organizer(request, &auth).await,
+            "/adherence" => adherence::handle_get_adherence(request, &auth).await,
+            "/ingredients" => ingredients::handle_get_ingredients(request, &auth).await,
+            _ => respond_404(),
+        }
     } else if request.method() == Method::POST {
-        handle_post(request).await
+        handle_post(request, &auth).await
     } else {
         respond_405("GET, POST")
     }
 }
 
 
+#[allow(clippy::len_zero, clippy::redundant_pattern_matching)]
 async fn perform() -> i32 {
     let args: Vec<OsString> = env::args_os().collect();
     if args.len() < 1 || args.len() > 2 {
@@ -619,6 +1053,18 @@ async fn perform() -> i32 {
                 return 1;
             },
         };
+        if let Err(e) = config.validate_patients() {
+            error!("invalid config file {:?}: {}", config_path, e);
+            return 1;
+        }
+        if let Err(e) = ingredients::parse_limits(&config.max_daily_doses) {
+            error!("invalid config file {:?}: {}", config_path, e);
+            return 1;
+        }
+        if let Err(e) = config.consumption_persist_interval() {
+            error!("invalid config file {:?}: {}", config_path, e);
+            return 1;
+        }
         if let Err(_) = CONFIG.set(RwLock::new(config)) {
             error!("failed to set initial config");
             return 1;
@@ -642,6 +1088,17 @@ async fn perform() -> i32 {
     let make_service = make_service_fn(|_conn| async {
         Ok::<_, Infallible>(service_fn(handle_request))
     });
+    let persist_interval = {
+        CONFIG
+            .get().expect("config is set")
+            .read().await
+            .consumption_persist_interval()
+            .expect("persist interval has been validated")
+    };
+    if let Some(period) = persist_interval {
+        tokio::spawn(persist_consumption_periodically(period));
+    }
+
     let server = Server::bind(&addr).serve(make_service);
     if let Err(e) = server.await {
         error!("server error: {}", e);
@@ -1,30 +1,121 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
+use chrono::{DateTime, Days, Duration, Local, NaiveDate, Utc};
 use derive_new::new;
 use num_rational::Rational64;
 use num_traits::Zero;
 use serde::{Deserialize, Serialize};
 
+use crate::schedule::{DailyDosage, DosagePhase, Frequency, MAX_DOSAGE_PLAN_DAYS};
+use crate::units::{Quantity, Unit};
+
+
+/// How many days ahead to warn about prescriptions expiring.
+pub(crate) const PRESCRIPTION_WARNING_DAYS: i64 = 30;
+
+/// The storage location of drugs that do not specify a primary location.
+pub(crate) const DEFAULT_LOCATION: &str = "home";
+
+/// The name of the patient whose data is stored at the top-level `data_path`.
+pub(crate) const DEFAULT_PATIENT: &str = "default";
+
+/// The longest interval at which the automatic consumption can be written to the data file.
+pub(crate) const MAX_CONSUMPTION_PERSIST_INTERVAL_MINUTES: u64 = 7 * 24 * 60;
+
 
 #[derive(Clone, Debug, Deserialize, Eq, new, PartialEq, Serialize)]
 pub(crate) struct Config {
     pub listen_addr: String,
     pub base_url: String,
-    pub data_path: String,
-    pub auth_tokens: Vec<String>,
+    /// The data file of the patient named [`DEFAULT_PATIENT`]; optional if `patients` are given.
+    #[serde(default)] pub data_path: Option<String>,
+    /// Further patients, each with a data file of their own.
+    #[serde(default)] #[new(default)] pub patients: Vec<Patient>,
+    pub auth_tokens: Vec<AuthToken>,
     pub column_profiles: HashMap<String, Vec<String>>,
+    /// If set, the consumption of automatically tracked drugs is written to the data file at this
+    /// interval; otherwise, it is only written along with the next modification.
+    #[serde(default)] pub consumption_persist_interval_minutes: Option<u64>,
+    /// When drugs are highlighted for replenishment, unless overridden by the drug.
+    #[serde(default)] pub replenishment: ReplenishmentThresholds,
+    /// The maximum daily dose of each active ingredient by generic name, e.g. "4 g".
+    #[serde(default)] #[new(default)] pub max_daily_doses: HashMap<String, String>,
+    /// The path to a CSV or JSON file listing interactions between active ingredients.
+    #[serde(default)] #[new(default)] pub interactions_path: Option<String>,
+}
+
+/// The thresholds determining when a drug should be replenished.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct ReplenishmentThresholds {
+    /// Number of days between ordering a drug and having it in stock.
+    #[serde(default)] pub lead_time_days: i64,
+    /// Number of weeks of consumption to have left over when a replenishment arrives.
+    #[serde(default = "ReplenishmentThresholds::default_safety_stock_weeks")] pub safety_stock_weeks: i64,
+    /// Number of days it takes to obtain a new prescription, added to the lead time when the
+    /// current prescription cannot be refilled anymore.
+    #[serde(default)] pub doctor_visit_days: i64,
 }
 
+/// Per-drug overrides of [`ReplenishmentThresholds`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct ReplenishmentOverrides {
+    #[serde(default)] pub lead_time_days: Option<i64>,
+    #[serde(default)] pub safety_stock_weeks: Option<i64>,
+    #[serde(default)] pub doctor_visit_days: Option<i64>,
+}
+
+/// A person whose drugs are managed, with a data file and journal of their own.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct Patient {
+    pub name: String,
+    pub data_path: String,
+}
+
+/// A token granting access, optionally with a label identifying its holder in the journal and
+/// limited to some of the patients.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum AuthToken {
+    Plain(String),
+    Detailed {
+        token: String,
+        #[serde(default)] label: Option<String>,
+        /// The names of the patients whose data the token grants access to; all patients if empty.
+        #[serde(default)] patients: Vec<String>,
+    },
+}
+
+#[allow(clippy::too_many_arguments)]
 #[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
 pub(crate) struct Drug {
+    #[serde(default)] #[new(default)] id: u64,
     trade_name: String,
     components: Vec<DrugComponent>,
     description: String,
+    /// The total stock; always equal to the sum of `batches`.
     remaining: Rational64,
+    /// The stock, split by expiry date and lot; consumed first-expiring-first.
+    #[serde(default)] #[new(default)] batches: Vec<StockBatch>,
     dosage_morning: Rational64,
     dosage_noon: Rational64,
     dosage_evening: Rational64,
     dosage_night: Rational64,
+    /// On which days the dosages above are taken.
+    #[serde(default)] #[new(default)] frequency: Frequency,
+    /// Dated phases with their own dosages (e.g. a taper or a time-limited course); if not empty,
+    /// they replace the dosages above and nothing is taken outside of them.
+    #[serde(default)] #[new(default)] dosage_plan: Vec<DosagePhase>,
+    /// Whether to stop showing the drug once its dosage plan has ended.
+    #[serde(default)] #[new(default)] hide_when_finished: bool,
+    /// Whether the drug is only taken as needed; its doses are logged individually instead of
+    /// following the dosages above.
+    #[serde(default)] #[new(default)] as_needed: bool,
+    /// Safety stock in units: at or below this stock, the drug should be replenished regardless of
+    /// how long the stock lasts. For drugs taken as needed, this is the only threshold applied.
+    #[serde(default)] #[new(default)] min_stock: Option<Rational64>,
+    /// Replenishment thresholds that differ from the configured ones.
+    #[serde(default)] #[new(default)] replenishment: ReplenishmentOverrides,
     units_per_package: Rational64,
     packages_per_prescription: Rational64,
     show: bool,
@@ -32,6 +123,14 @@ pub(crate) struct Drug {
     reverse_photo: Option<String>,
     #[serde(default)] is_pill: bool,
     #[serde(default = "Drug::default_in_replenishment_cycle")] in_replenishment_cycle: bool,
+    /// The prescription the drug is currently obtained with, if known.
+    #[serde(default)] #[new(default)] prescription: Option<Prescription>,
+    /// The storage location from which the drug is consumed and into which it is replenished;
+    /// [`DEFAULT_LOCATION`] if not set.
+    #[serde(default)] #[new(default)] primary_location: Option<String>,
+    /// When the stock was last counted (or deducted) if consumption is tracked automatically;
+    /// `None` if the stock is only reduced manually.
+    #[serde(default)] #[new(default)] stock_as_of: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
@@ -41,12 +140,59 @@ pub(crate) struct DrugComponent {
     unit: String,
 }
 
+/// A part of the stock of a drug that expires at the same time, usually one package.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
+pub(crate) struct StockBatch {
+    amount: Rational64,
+    expiry: Option<NaiveDate>,
+    lot: Option<String>,
+    /// Where the batch is stored; if empty, the drug's primary location.
+    #[serde(default)] #[new(default)] location: String,
+}
+
+/// The total stock of a drug at one storage location.
+#[derive(Clone, Debug, Eq, Hash, new, PartialEq, Serialize)]
+pub(crate) struct LocationStock {
+    location: String,
+    amount: Rational64,
+}
+
+/// A prescription that can be filled a limited number of times.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
+pub(crate) struct Prescription {
+    prescriber: String,
+    issued: NaiveDate,
+    expires: Option<NaiveDate>,
+    /// How many more times the prescription can be filled.
+    refills_remaining: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
 #[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
 pub(crate) struct DrugToDisplay {
+    /// Position of the drug within the data file; only kept for clients that do not know about
+    /// drug IDs yet.
     pub index: usize,
     pub drug: Drug,
+    pub remaining_days: Option<i64>,
     pub remaining_weeks: Option<i64>,
     pub weeks_per_prescription: Option<i64>,
+    /// The dosage in effect today.
+    pub dosage: DailyDosage,
+    /// For drugs taken as needed, the average amount taken per day recently.
+    pub average_dose_day: Option<Rational64>,
+    /// The thresholds applying to this drug.
+    pub thresholds: ReplenishmentThresholds,
+    /// The day on which the stock is projected to run out.
+    pub run_out_date: Option<NaiveDate>,
+    /// The last day on which a replenishment can be requested to arrive before the stock runs out.
+    pub refill_by_date: Option<NaiveDate>,
+    /// Whether the next replenishment requires a new prescription from a doctor.
+    pub needs_doctor_visit: bool,
+    /// Problems with the drug's prescription that the user should be made aware of.
+    pub prescription_warnings: Vec<String>,
+    /// Stock that has expired or will expire before it is used up.
+    pub expiry_warnings: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, new, PartialEq, Serialize)]
@@ -62,76 +208,810 @@ pub(crate) enum ReplenishmentStatus {
     DoNot,
     Can,
     Should,
+    /// Not even a replenishment ordered right away will arrive before the stock runs out.
+    Late,
 }
 
+/// The unit in which a quantity of a drug is given when replenishing.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum QuantityUnit {
+    /// Single units such as pills.
+    #[default]
+    Units,
+    Packages,
+    /// Everything obtained with one prescription.
+    Prescriptions,
+}
+
+
+impl Config {
+    /// All patients: the one whose data is stored at `data_path`, if given, followed by `patients`.
+    pub fn all_patients(&self) -> Vec<Patient> {
+        let mut patients = Vec::with_capacity(self.patients.len() + 1);
+        if let Some(data_path) = &self.data_path {
+            patients.push(Patient {
+                name: DEFAULT_PATIENT.to_owned(),
+                data_path: data_path.clone(),
+            });
+        }
+        patients.extend(self.patients.iter().cloned());
+        patients
+    }
+
+    /// Checks that there is at least one patient, that the names of the patients are unique and
+    /// that the tokens only refer to existing patients.
+    pub fn validate_patients(&self) -> Result<(), String> {
+        let patients = self.all_patients();
+        if patients.is_empty() {
+            return Err("either \"data_path\" or \"patients\" must be given".to_owned());
+        }
+        let mut names = HashSet::new();
+        for patient in &patients {
+            if patient.name.trim().is_empty() {
+                return Err("patient names must not be empty".to_owned());
+            }
+            if !names.insert(patient.name.as_str()) {
+                return Err(format!("patient name {:?} is used more than once", patient.name));
+            }
+        }
+        for auth_token in &self.auth_tokens {
+            for name in auth_token.patients() {
+                if !names.contains(name.as_str()) {
+                    return Err(format!("token refers to unknown patient {:?}", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The interval at which the automatic consumption is written to the data file, if any.
+    ///
+    /// Fails if the interval is longer than [`MAX_CONSUMPTION_PERSIST_INTERVAL_MINUTES`].
+    pub fn consumption_persist_interval(&self) -> Result<Option<std::time::Duration>, String> {
+        match self.consumption_persist_interval_minutes {
+            None|Some(0) => Ok(None),
+            Some(minutes) if minutes <= MAX_CONSUMPTION_PERSIST_INTERVAL_MINUTES => {
+                Ok(Some(std::time::Duration::from_secs(minutes * 60)))
+            },
+            Some(_) => Err(format!(
+                "\"consumption_persist_interval_minutes\" must not exceed {}",
+                MAX_CONSUMPTION_PERSIST_INTERVAL_MINUTES,
+            )),
+        }
+    }
+}
+
+impl Patient {
+    /// The journal is kept next to the data file, with `.journal` appended to its name.
+    pub fn journal_path(&self) -> PathBuf {
+        let mut path = self.data_path.clone();
+        path.push_str(".journal");
+        PathBuf::from(path)
+    }
+}
+
+impl AuthToken {
+    pub fn token(&self) -> &str {
+        match self {
+            Self::Plain(token) => token,
+            Self::Detailed { token, .. } => token,
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Detailed { label, .. } => label.as_deref(),
+        }
+    }
+
+    pub fn patients(&self) -> &[String] {
+        match self {
+            Self::Plain(_) => &[],
+            Self::Detailed { patients, .. } => patients,
+        }
+    }
+
+    /// Whether the token grants access to the data of the given patient.
+    pub fn may_access(&self, patient: &Patient) -> bool {
+        let patients = self.patients();
+        patients.is_empty() || patients.contains(&patient.name)
+    }
+}
 
 impl Drug {
+    pub fn id(&self) -> u64 { self.id }
     pub fn trade_name(&self) -> &str { &self.trade_name }
     pub fn components(&self) -> &Vec<DrugComponent> { &self.components }
     pub fn description(&self) -> &str { &self.description }
     pub fn remaining(&self) -> Rational64 { self.remaining }
+    pub fn batches(&self) -> &[StockBatch] { &self.batches }
+    pub fn primary_location(&self) -> &str { self.primary_location.as_deref().unwrap_or(DEFAULT_LOCATION) }
     pub fn dosage_morning(&self) -> Rational64 { self.dosage_morning }
     pub fn dosage_noon(&self) -> Rational64 { self.dosage_noon }
     pub fn dosage_evening(&self) -> Rational64 { self.dosage_evening }
     pub fn dosage_night(&self) -> Rational64 { self.dosage_night }
+    pub fn frequency(&self) -> &Frequency { &self.frequency }
+    pub fn dosage_plan(&self) -> &[DosagePhase] { &self.dosage_plan }
+    pub fn hide_when_finished(&self) -> bool { self.hide_when_finished }
+    pub fn as_needed(&self) -> bool { self.as_needed }
+    pub fn min_stock(&self) -> Option<Rational64> { self.min_stock }
+    pub fn replenishment(&self) -> ReplenishmentOverrides { self.replenishment }
     pub fn units_per_package(&self) -> Rational64 { self.units_per_package }
     pub fn packages_per_prescription(&self) -> Rational64 { self.packages_per_prescription }
     pub fn show(&self) -> bool { self.show }
+    #[allow(clippy::option_as_ref_deref)]
     pub fn obverse_photo(&self) -> Option<&str> { self.obverse_photo.as_ref().map(|s| s.as_str()) }
+    #[allow(clippy::option_as_ref_deref)]
     pub fn reverse_photo(&self) -> Option<&str> { self.reverse_photo.as_ref().map(|s| s.as_str()) }
     pub fn is_pill(&self) -> bool { self.is_pill }
+    pub fn prescription(&self) -> Option<&Prescription> { self.prescription.as_ref() }
     pub fn in_replenishment_cycle(&self) -> bool { self.in_replenishment_cycle }
+    pub fn tracks_consumption(&self) -> bool { self.stock_as_of.is_some() }
 
+    /// The average amount taken per day according to the dosage; zero for drugs taken as needed.
     pub fn total_dosage_day(&self) -> Rational64 {
-        self.dosage_morning + self.dosage_noon + self.dosage_evening + self.dosage_night
+        if self.as_needed {
+            return Zero::zero();
+        }
+        let dose = self.dosage_morning + self.dosage_noon + self.dosage_evening + self.dosage_night;
+        dose * self.frequency.average_per_day()
+    }
+
+    /// The dosage in effect on the given date, regardless of whether the frequency has it taken on
+    /// that date.
+    pub fn scheduled_dosage(&self, date: NaiveDate) -> DailyDosage {
+        if self.dosage_plan.is_empty() {
+            return DailyDosage {
+                morning: self.dosage_morning,
+                noon: self.dosage_noon,
+                evening: self.dosage_evening,
+                night: self.dosage_night,
+            };
+        }
+        self.dosage_plan.iter()
+            .find(|phase| phase.contains(date))
+            .map(|phase| phase.dosage)
+            .unwrap_or_else(DailyDosage::zero)
+    }
+
+    /// The amounts taken on the given date.
+    pub fn dosage_on(&self, date: NaiveDate) -> DailyDosage {
+        if self.as_needed || !self.frequency.applies_on(date) {
+            return DailyDosage::zero();
+        }
+        self.scheduled_dosage(date)
+    }
+
+    /// The last day of the dosage plan, or `None` if the drug is taken indefinitely.
+    pub fn plan_end(&self) -> Option<NaiveDate> {
+        self.dosage_plan.iter()
+            .map(|phase| phase.end)
+            .max()
+    }
+
+    /// The average amount taken per day from `today` until the end of the dosage plan.
+    ///
+    /// Equals [`Drug::total_dosage_day`] for drugs without a dosage plan.
+    pub fn remaining_dosage_day(&self, today: NaiveDate) -> Rational64 {
+        let end_date = match self.plan_end() {
+            Some(end) => end.succ_opt().unwrap_or(end).min(plan_horizon(today)),
+            None => return self.total_dosage_day(),
+        };
+        let days = (end_date - today).num_days();
+        if days <= 0 {
+            return Zero::zero();
+        }
+        self.consumption_between(today, end_date) / Rational64::from_integer(days)
+    }
+
+    /// The number of full days, starting with `today`, that the stock covers according to the
+    /// dosage plan, or `None` if the stock outlasts the plan.
+    ///
+    /// Only meaningful for drugs with a dosage plan.
+    pub fn planned_days_of_stock(&self, today: NaiveDate) -> Option<i64> {
+        let end = self.plan_end()?.min(plan_horizon(today));
+        let mut stock = self.remaining;
+        let mut days = 0;
+        let mut date = today;
+        while date <= end {
+            stock -= self.dosage_on(date).total();
+            if stock < Zero::zero() {
+                return Some(days);
+            }
+            days += 1;
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    /// Stops showing the drug if its dosage plan ended before `today` and it is meant to be hidden
+    /// then.
+    ///
+    /// Returns whether the drug has been hidden.
+    pub fn hide_if_finished(&mut self, today: NaiveDate) -> bool {
+        if !self.hide_when_finished || !self.show {
+            return false;
+        }
+        match self.plan_end() {
+            Some(end) if end < today => {
+                self.show = false;
+                // only once, so that the drug can be shown again manually
+                self.hide_when_finished = false;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// The amount taken from `first_date` up to, but excluding, `end_date`.
+    pub fn consumption_between(&self, first_date: NaiveDate, end_date: NaiveDate) -> Rational64 {
+        self.slot_consumption_between(first_date, end_date).total()
+    }
+
+    /// The amounts taken at each time of day from `first_date` up to, but excluding, `end_date`.
+    pub fn slot_consumption_between(&self, first_date: NaiveDate, end_date: NaiveDate) -> DailyDosage {
+        let mut total = DailyDosage::zero();
+        let mut date = first_date;
+        while date < end_date {
+            let dosage = self.dosage_on(date);
+            total.morning += dosage.morning;
+            total.noon += dosage.noon;
+            total.evening += dosage.evening;
+            total.night += dosage.night;
+            date = match date.succ_opt() {
+                Some(d) => d,
+                None => break,
+            };
+        }
+        total
     }
 
     pub fn units_per_prescription(&self) -> Rational64 {
         self.units_per_package * self.packages_per_prescription
     }
 
+    /// Converts a quantity of this drug given in `unit` into single units.
+    pub fn to_units(&self, quantity: Rational64, unit: QuantityUnit) -> Rational64 {
+        match unit {
+            QuantityUnit::Units => quantity,
+            QuantityUnit::Packages => quantity * self.units_per_package,
+            QuantityUnit::Prescriptions => quantity * self.units_per_prescription(),
+        }
+    }
+
+    /// Reduces the stock, consuming the batches at the primary location before those elsewhere and
+    /// the batches that expire first before the others; batches without an expiry date are consumed
+    /// last.
+    #[allow(clippy::assign_op_pattern)]
     pub fn reduce(&mut self, subtrahend: &Rational64) {
         let zero: Rational64 = Zero::zero();
         assert!(subtrahend > &zero);
+        self.normalize_batches();
+
+        let primary_location = self.primary_location().to_owned();
+        let mut order: Vec<usize> = (0..self.batches.len()).collect();
+        order.sort_by_key(|&i| {
+            let batch = &self.batches[i];
+            (batch.location != primary_location, batch.expiry.is_none(), batch.expiry)
+        });
+        let mut left = *subtrahend;
+        for i in order {
+            let taken = left.min(self.batches[i].amount);
+            self.batches[i].amount -= taken;
+            left -= taken;
+            if left.is_zero() {
+                break;
+            }
+        }
+        self.batches.retain(|b| b.amount > zero);
+
         self.remaining = self.remaining - *subtrahend;
         if self.remaining < zero {
             self.remaining = zero;
         }
     }
 
+    /// Adds stock of unknown expiry.
     pub fn replenish(&mut self, addend: &Rational64) {
+        self.add_batch(StockBatch::new(*addend, None, None));
+    }
+
+    /// Adds a batch to the stock; batches without a location are stored at the primary location.
+    ///
+    /// Returns the change of the stock.
+    pub fn add_batch(&mut self, mut batch: StockBatch) -> Rational64 {
         let zero: Rational64 = Zero::zero();
-        assert!(addend > &zero);
-        self.remaining = self.remaining + *addend;
+        assert!(batch.amount > zero);
+        self.normalize_batches();
+        if batch.location.is_empty() {
+            batch.location = self.primary_location().to_owned();
+        }
+        let delta = batch.amount;
+        self.remaining += delta;
+        self.batches.push(batch);
+        delta
+    }
+
+    /// Removes stock from the most recently added batches, reverting an earlier replenishment.
+    ///
+    /// Returns the change actually applied.
+    pub fn remove_newest(&mut self, amount: &Rational64) -> Rational64 {
+        let zero: Rational64 = Zero::zero();
+        assert!(amount > &zero);
+        self.normalize_batches();
+        let before = self.remaining;
+        let mut left = *amount;
+        while let Some(batch) = self.batches.last_mut() {
+            let taken = left.min(batch.amount);
+            batch.amount -= taken;
+            left -= taken;
+            if batch.amount.is_zero() {
+                self.batches.pop();
+            }
+            if left.is_zero() {
+                break;
+            }
+        }
+        self.remaining = self.batches.iter()
+            .map(|b| b.amount)
+            .sum();
+        self.remaining - before
+    }
+
+    /// Brings the batches in line with the total stock: stock not covered by any batch (e.g. from
+    /// data files predating batches) becomes a batch of unknown expiry, while excess batches are
+    /// consumed. Batches without a location are moved to the primary location.
+    pub fn normalize_batches(&mut self) {
+        let zero: Rational64 = Zero::zero();
+        let primary_location = self.primary_location().to_owned();
+        for batch in &mut self.batches {
+            if batch.location.is_empty() {
+                batch.location = primary_location.clone();
+            }
+        }
+        let batch_total: Rational64 = self.batches.iter()
+            .map(|b| b.amount)
+            .sum();
+        if batch_total < self.remaining {
+            let mut batch = StockBatch::new(self.remaining - batch_total, None, None);
+            batch.location = primary_location;
+            self.batches.push(batch);
+        } else if batch_total > self.remaining {
+            let target = self.remaining;
+            self.remaining = batch_total;
+            self.reduce(&(batch_total - target));
+        }
+        self.batches.retain(|b| b.amount > zero);
+    }
+
+    /// Takes over the batches of `previous`, a former version of this drug, and applies the
+    /// difference between its stock and the stock of this version to them.
+    ///
+    /// Returns the difference.
+    pub fn carry_over_batches(&mut self, previous: &Drug) -> Rational64 {
+        let target = self.remaining;
+        self.batches = previous.batches.clone();
+        self.remaining = previous.remaining;
+        self.adjust(&(target - previous.remaining))
+    }
+
+    /// Keeps the point in time up to which the consumption of `previous`, a former version of this
+    /// drug, has been deducted if both versions track consumption.
+    pub fn carry_over_consumption_tracking(&mut self, previous: &Drug) {
+        if self.stock_as_of.is_some() && previous.stock_as_of.is_some() {
+            self.stock_as_of = previous.stock_as_of;
+        }
+    }
+
+    /// The total stock at each storage location, the primary location first and the others in
+    /// alphabetical order.
+    pub fn stock_by_location(&self) -> Vec<LocationStock> {
+        let primary_location = self.primary_location();
+        let mut stocks: Vec<LocationStock> = Vec::new();
+        for batch in &self.batches {
+            let location = if batch.location.is_empty() { primary_location } else { &batch.location };
+            match stocks.iter_mut().find(|s| s.location == location) {
+                Some(stock) => stock.amount += batch.amount,
+                None => stocks.push(LocationStock::new(location.to_owned(), batch.amount)),
+            }
+        }
+        stocks.sort_by_key(|s| (s.location != primary_location, s.location.clone()));
+        stocks
+    }
+
+    /// The total stock at the given storage location.
+    pub fn stock_at(&self, location: &str) -> Rational64 {
+        self.stock_by_location().iter()
+            .filter(|s| s.location == location)
+            .map(|s| s.amount)
+            .sum()
+    }
+
+    /// Moves `amount` units from one storage location to another, taking the batches that expire
+    /// first.
+    pub fn transfer(&mut self, amount: &Rational64, from: &str, to: &str) -> Result<(), &'static str> {
+        let zero: Rational64 = Zero::zero();
+        if amount <= &zero {
+            return Err("the amount to transfer must be positive");
+        }
+        if from == to {
+            return Err("the locations must be different");
+        }
+        if to.is_empty() {
+            return Err("the target location must not be empty");
+        }
+        self.normalize_batches();
+        let available: Rational64 = self.batches.iter()
+            .filter(|b| b.location == from)
+            .map(|b| b.amount)
+            .sum();
+        if available < *amount {
+            return Err("not enough stock at that location");
+        }
+
+        let mut order: Vec<usize> = (0..self.batches.len())
+            .filter(|&i| self.batches[i].location == from)
+            .collect();
+        order.sort_by_key(|&i| (self.batches[i].expiry.is_none(), self.batches[i].expiry));
+        let mut left = *amount;
+        let mut moved = Vec::new();
+        for i in order {
+            let taken = left.min(self.batches[i].amount);
+            self.batches[i].amount -= taken;
+            left -= taken;
+            let mut part = self.batches[i].clone();
+            part.amount = taken;
+            part.location = to.to_owned();
+            moved.push(part);
+            if left.is_zero() {
+                break;
+            }
+        }
+        self.batches.retain(|b| b.amount > zero);
+        self.batches.extend(moved);
+        Ok(())
+    }
+
+    /// The batches that expired before `today`.
+    pub fn expired_batches(&self, today: NaiveDate) -> Vec<&StockBatch> {
+        self.batches.iter()
+            .filter(|b| b.is_expired(today))
+            .collect()
+    }
+
+    /// Removes the batches that expired before `today`.
+    ///
+    /// Returns the change of the stock.
+    pub fn discard_expired(&mut self, today: NaiveDate) -> Rational64 {
+        self.normalize_batches();
+        let before = self.remaining;
+        self.batches.retain(|b| !b.is_expired(today));
+        self.remaining = self.batches.iter()
+            .map(|b| b.amount)
+            .sum();
+        self.remaining - before
+    }
+
+    /// Finds the amounts that will expire before they are used up if `dosage_day` is consumed each
+    /// day from `today` onward, first-expiring-first.
+    ///
+    /// Returns pairs of expiry date and amount left over on that date.
+    pub fn unused_at_expiry(&self, today: NaiveDate, dosage_day: Rational64) -> Vec<(NaiveDate, Rational64)> {
+        let mut batches: Vec<&StockBatch> = self.batches.iter()
+            .filter(|b| !b.is_expired(today))
+            .collect();
+        batches.sort_by_key(|b| (b.expiry.is_none(), b.expiry));
+
+        let mut unused = Vec::new();
+        let mut consumed: Rational64 = Zero::zero();
+        for batch in batches {
+            let expiry = match batch.expiry {
+                Some(e) => e,
+                None => break,
+            };
+            // the batch can still be used on the day it expires
+            let days_until_expiry = Rational64::from_integer((expiry - today).num_days() + 1);
+            let usable = (days_until_expiry * dosage_day - consumed)
+                .max(Zero::zero())
+                .min(batch.amount);
+            consumed += usable;
+            if usable < batch.amount {
+                unused.push((expiry, batch.amount - usable));
+            }
+        }
+        unused
+    }
+
+    /// Returns warnings about expired stock and stock that will expire before it is used up at
+    /// `dosage_day` units per day.
+    pub fn expiry_warnings(&self, today: NaiveDate, dosage_day: Rational64) -> Vec<String> {
+        let format_amount = |amount: Rational64| crate::filters::frac2rounded(amount)
+            .unwrap_or_default();
+        let mut warnings = Vec::new();
+        for batch in self.expired_batches(today) {
+            if let Some(expiry) = batch.expiry {
+                warnings.push(format!("{} expired on {}", format_amount(batch.amount), expiry));
+            }
+        }
+        if dosage_day > Zero::zero() {
+            for (expiry, amount) in self.unused_at_expiry(today, dosage_day) {
+                warnings.push(format!("{} will expire unused on {}", format_amount(amount), expiry));
+            }
+        }
+        warnings
+    }
+
+    /// Replenishes the stock if `amount` is positive and reduces it if `amount` is negative.
+    ///
+    /// Returns the change actually applied, which differs from `amount` if the stock would have
+    /// dropped below zero.
+    pub fn adjust(&mut self, amount: &Rational64) -> Rational64 {
+        let zero: Rational64 = Zero::zero();
+        let before = self.remaining;
+        if amount < &zero {
+            self.reduce(&-*amount);
+        } else if amount > &zero {
+            self.replenish(amount);
+        }
+        self.remaining - before
+    }
+
+    /// Reduces the stock by the amount consumed within the given number of days, ending with (and
+    /// including) `today`.
+    ///
+    /// Drugs whose consumption is tracked automatically are left alone, since their consumption is
+    /// already being deducted.
+    ///
+    /// Returns the change actually applied, or an error if the days reach beyond the supported
+    /// range of dates.
+    pub fn take_days(&mut self, days: i64, today: NaiveDate) -> Result<Rational64, &'static str> {
+        let end_date = today.checked_add_days(Days::new(1))
+            .ok_or("date out of range")?;
+        let first_date = u64::try_from(days).ok()
+            .and_then(|d| end_date.checked_sub_days(Days::new(d)))
+            .ok_or("date out of range")?;
+        Ok(self.take_between(first_date, end_date))
+    }
+
+    /// Reduces the stock by the amount consumed from `first_date` up to, but excluding,
+    /// `end_date`, e.g. when filling a pill organizer.
+    ///
+    /// Like [`Drug::take_days`], leaves drugs whose consumption is tracked automatically alone.
+    /// Returns the change actually applied.
+    pub fn take_between(&mut self, first_date: NaiveDate, end_date: NaiveDate) -> Rational64 {
+        if self.tracks_consumption() {
+            return Zero::zero();
+        }
+        let dose = self.consumption_between(first_date, end_date);
+        self.adjust(&-dose)
+    }
+
+    /// Deducts the doses taken on each full day that has passed between the last count and `now`.
+    ///
+    /// Does nothing if the consumption of this drug is not tracked automatically. Returns the change
+    /// actually applied.
+    pub fn consume_until(&mut self, now: DateTime<Utc>) -> Rational64 {
+        let as_of = match self.stock_as_of {
+            Some(ao) => ao,
+            None => return Zero::zero(),
+        };
+        let days = (now - as_of).num_days();
+        if days <= 0 {
+            return Zero::zero();
+        }
+
+        // only move forward by full days to keep the remainder for the next deduction
+        let new_as_of = as_of + Duration::days(days);
+        self.stock_as_of = Some(new_as_of);
+        let consumed = self.consumption_between(
+            as_of.with_timezone(&Local).date_naive(),
+            new_as_of.with_timezone(&Local).date_naive(),
+        );
+        self.adjust(&-consumed)
+    }
+
+    /// Enables or disables automatic consumption tracking; when enabling, the current stock is
+    /// taken to have been counted at `now`.
+    pub fn set_tracks_consumption(&mut self, tracks_consumption: bool, now: DateTime<Utc>) {
+        self.stock_as_of = if tracks_consumption { Some(now) } else { None };
+    }
+
+    pub fn set_id(&mut self, id: u64) { self.id = id; }
+    pub fn set_frequency(&mut self, frequency: Frequency) { self.frequency = frequency; }
+    pub fn set_as_needed(&mut self, as_needed: bool) { self.as_needed = as_needed; }
+    pub fn set_prescription(&mut self, prescription: Option<Prescription>) { self.prescription = prescription; }
+    pub fn set_primary_location(&mut self, primary_location: Option<String>) { self.primary_location = primary_location; }
+
+    /// Uses up one refill of the drug's prescription.
+    pub fn use_prescription_refill(&mut self, today: NaiveDate) -> Result<(), &'static str> {
+        let prescription = self.prescription.as_mut()
+            .ok_or("drug has no prescription")?;
+        if prescription.is_expired(today) {
+            return Err("prescription has expired");
+        }
+        if prescription.refills_remaining == 0 {
+            return Err("prescription has no refills left");
+        }
+        prescription.refills_remaining -= 1;
+        Ok(())
+    }
+
+    /// Gives back a refill used up by [`Drug::use_prescription_refill`].
+    pub fn restore_prescription_refill(&mut self) {
+        if let Some(prescription) = self.prescription.as_mut() {
+            prescription.refills_remaining += 1;
+        }
+    }
+    pub fn set_replenishment(&mut self, min_stock: Option<Rational64>, overrides: ReplenishmentOverrides) {
+        self.min_stock = min_stock;
+        self.replenishment = overrides;
+    }
+    pub fn set_dosage_plan(&mut self, dosage_plan: Vec<DosagePhase>, hide_when_finished: bool) {
+        self.dosage_plan = dosage_plan;
+        self.hide_when_finished = hide_when_finished;
     }
 
     pub fn default_in_replenishment_cycle() -> bool { true }
 }
 
+#[cfg(test)]
+impl Drug {
+    /// A shown pill with the given ID and stock, taken once in the morning and packaged and
+    /// prescribed one unit at a time; a starting point for drugs used in tests.
+    pub(crate) fn for_test(id: u64, remaining: i64) -> Self {
+        use num_traits::One;
+
+        let mut drug = Self::new(
+            format!("drug {}", id), Vec::new(), String::new(),
+            Rational64::from_integer(remaining), One::one(), Zero::zero(), Zero::zero(), Zero::zero(),
+            One::one(), One::one(),
+            true, None, None, true, true,
+        );
+        drug.id = id;
+        drug
+    }
+
+    pub(crate) fn with_dosage(mut self, morning: Rational64, noon: Rational64, evening: Rational64, night: Rational64) -> Self {
+        self.dosage_morning = morning;
+        self.dosage_noon = noon;
+        self.dosage_evening = evening;
+        self.dosage_night = night;
+        self
+    }
+
+    pub(crate) fn with_components(mut self, components: Vec<DrugComponent>) -> Self {
+        self.components = components;
+        self
+    }
+}
+
 impl DrugComponent {
     pub fn generic_name(&self) -> &str { &self.generic_name }
     pub fn amount(&self) -> Rational64 { self.amount }
     pub fn unit(&self) -> &str { &self.unit }
+
+    /// The amount along with its unit, or `None` if the unit is not known.
+    pub fn quantity(&self) -> Option<Quantity> {
+        Unit::parse(&self.unit)
+            .map(|unit| Quantity::new(self.amount, unit))
+    }
+
+    /// The unit as it should be displayed: its usual symbol if it is known, otherwise as entered.
+    pub fn unit_symbol(&self) -> &str {
+        Unit::parse(&self.unit)
+            .map(|unit| unit.symbol())
+            .unwrap_or(&self.unit)
+    }
+}
+
+impl LocationStock {
+    pub fn location(&self) -> &str { &self.location }
+    pub fn amount(&self) -> Rational64 { self.amount }
+}
+
+impl StockBatch {
+    pub fn amount(&self) -> Rational64 { self.amount }
+    pub fn expiry(&self) -> Option<NaiveDate> { self.expiry }
+    pub fn lot(&self) -> Option<&str> { self.lot.as_deref() }
+    pub fn location(&self) -> &str { &self.location }
+
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        self.expiry
+            .map(|expiry| expiry < today)
+            .unwrap_or(false)
+    }
+}
+
+impl Prescription {
+    pub fn prescriber(&self) -> &str { &self.prescriber }
+    pub fn issued(&self) -> NaiveDate { self.issued }
+    pub fn expires(&self) -> Option<NaiveDate> { self.expires }
+    pub fn refills_remaining(&self) -> u32 { self.refills_remaining }
+
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        self.expires
+            .map(|expires| expires < today)
+            .unwrap_or(false)
+    }
+
+    /// Whether the prescription can still be filled on the given date.
+    pub fn can_refill(&self, date: NaiveDate) -> bool {
+        self.refills_remaining > 0 && !self.is_expired(date)
+    }
+
+    /// Returns warnings about the prescription running out, looking `warning_days` days ahead.
+    pub fn warnings(&self, today: NaiveDate, warning_days: i64) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.refills_remaining == 0 {
+            warnings.push("no refills left".to_owned());
+        } else if self.refills_remaining == 1 {
+            warnings.push("last refill".to_owned());
+        }
+        if let Some(expires) = self.expires {
+            if expires < today {
+                warnings.push(format!("expired on {}", expires));
+            } else if expires <= today + Duration::days(warning_days) {
+                warnings.push(format!("expires on {}", expires));
+            }
+        }
+        warnings
+    }
+}
+
+impl ReplenishmentThresholds {
+    pub fn default_safety_stock_weeks() -> i64 { 3 }
+
+    /// Returns these thresholds with the given overrides applied.
+    pub fn overridden_by(&self, overrides: &ReplenishmentOverrides) -> Self {
+        Self {
+            lead_time_days: overrides.lead_time_days.unwrap_or(self.lead_time_days),
+            safety_stock_weeks: overrides.safety_stock_weeks.unwrap_or(self.safety_stock_weeks),
+            doctor_visit_days: overrides.doctor_visit_days.unwrap_or(self.doctor_visit_days),
+        }
+    }
+}
+
+impl Default for ReplenishmentThresholds {
+    fn default() -> Self {
+        Self {
+            lead_time_days: 0,
+            safety_stock_weeks: Self::default_safety_stock_weeks(),
+            doctor_visit_days: 0,
+        }
+    }
 }
 
 impl DrugToDisplay {
     #[allow(unused)] pub fn index(&self) -> usize { self.index }
     pub fn drug(&self) -> &Drug { &self.drug }
-    pub fn remaining_weeks(&self) -> Option<i64> { self.remaining_weeks }
     pub fn weeks_per_prescription(&self) -> Option<i64> { self.weeks_per_prescription }
+    pub fn average_dose_day(&self) -> Option<Rational64> { self.average_dose_day }
 
     pub fn needs_replenishment(&self, min_weeks_per_prescription: &Option<i64>) -> ReplenishmentStatus {
-        let mwpp = match min_weeks_per_prescription {
-            Some(m) => *m,
-            None => return ReplenishmentStatus::DoNot,
-        };
-        let rw = match self.remaining_weeks() {
-            Some(w) => w,
-            None => return ReplenishmentStatus::DoNot,
+        let below_min_stock = self.drug.min_stock()
+            .map(|min_stock| self.drug.remaining() <= min_stock)
+            .unwrap_or(false);
+        if self.drug.as_needed() {
+            // usage is too irregular for the weeks to be meaningful
+            return if below_min_stock {
+                ReplenishmentStatus::Should
+            } else {
+                ReplenishmentStatus::DoNot
+            };
+        }
+
+        let lead_time = self.thresholds.lead_time_days;
+        let rd = match self.remaining_days {
+            Some(d) => d,
+            None => return if below_min_stock { ReplenishmentStatus::Should } else { ReplenishmentStatus::DoNot },
         };
-        if rw < 3 {
+        if rd < lead_time {
+            ReplenishmentStatus::Late
+        } else if below_min_stock || rd < lead_time + 7*self.thresholds.safety_stock_weeks {
             ReplenishmentStatus::Should
-        } else if rw < mwpp {
+        } else if min_weeks_per_prescription.map(|mwpp| rd < lead_time + 7*mwpp).unwrap_or(false) {
             ReplenishmentStatus::Can
         } else {
             ReplenishmentStatus::DoNot
@@ -170,12 +1050,389 @@ impl DailyPills {
     }
 }
 
+impl QuantityUnit {
+    /// Parses the value of the unit selection of the replenish form.
+    pub fn from_form_value(value: &str) -> Option<Self> {
+        match value {
+            "units" => Some(Self::Units),
+            "packages" => Some(Self::Packages),
+            "prescriptions" => Some(Self::Prescriptions),
+            _ => None,
+        }
+    }
+}
+
 impl ReplenishmentStatus {
     pub fn css_classes(&self) -> &'static str {
         match self {
             Self::DoNot => "do-not-replenish",
             Self::Can => "replenish-me replenish-soon",
             Self::Should => "replenish-me replenish-now",
+            Self::Late => "replenish-me replenish-now replenish-late",
+        }
+    }
+}
+
+
+/// Returns the ID to assign to the next drug added to the list.
+///
+/// IDs are never reused, since the journal refers to drugs by their ID; `highest_used_id` is the
+/// highest ID assigned so far, including to drugs that have been deleted since (see
+/// [`crate::journal::highest_drug_id`]).
+pub(crate) fn next_drug_id(drugs: &[Drug], highest_used_id: u64) -> u64 {
+    drugs.iter()
+        .map(|d| d.id)
+        .max()
+        .unwrap_or(0)
+        .max(highest_used_id) + 1
+}
+
+/// Returns whether any drug has no ID or shares its ID with another drug.
+pub(crate) fn lacks_ids(drugs: &[Drug]) -> bool {
+    let mut seen_ids = HashSet::new();
+    drugs.iter()
+        .any(|d| d.id == 0 || !seen_ids.insert(d.id))
+}
+
+/// Assigns a fresh ID to each drug that has none (or shares its ID with a drug earlier in the
+/// list). Returns whether any ID has been changed.
+pub(crate) fn assign_missing_ids(drugs: &mut [Drug], highest_used_id: u64) -> bool {
+    let mut next_id = next_drug_id(drugs, highest_used_id);
+    let mut seen_ids = HashSet::new();
+    let mut changed = false;
+    for drug in drugs {
+        if drug.id == 0 || !seen_ids.insert(drug.id) {
+            drug.id = next_id;
+            seen_ids.insert(next_id);
+            next_id += 1;
+            changed = true;
         }
     }
+    changed
+}
+
+/// Finds the position of the drug with the given ID.
+pub(crate) fn find_drug_by_id(drugs: &[Drug], id: u64) -> Option<usize> {
+    drugs.iter()
+        .position(|d| d.id == id)
+}
+
+/// Returns the names of all storage locations in use by any drug, in alphabetical order.
+pub(crate) fn known_locations(drugs: &[Drug]) -> Vec<String> {
+    let mut locations: Vec<String> = drugs.iter()
+        .flat_map(|d| d.stock_by_location())
+        .map(|s| s.location)
+        .chain(drugs.iter().map(|d| d.primary_location().to_owned()))
+        .collect();
+    locations.sort();
+    locations.dedup();
+    locations
+}
+
+/// The last day that dosage plans are looked at from `today`; later days may only be part of plans
+/// saved before their length was limited.
+fn plan_horizon(today: NaiveDate) -> NaiveDate {
+    today.checked_add_days(Days::new(MAX_DOSAGE_PLAN_DAYS as u64))
+        .unwrap_or(NaiveDate::MAX)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_traits::{One, Zero};
+    use super::*;
+
+    #[test]
+    fn test_assign_missing_ids() {
+        let mut drugs = vec![Drug::for_test(0, 0), Drug::for_test(3, 0), Drug::for_test(0, 0), Drug::for_test(3, 0)];
+        assert!(lacks_ids(&drugs));
+        assert!(assign_missing_ids(&mut drugs, 0));
+        let ids: Vec<u64> = drugs.iter().map(|d| d.id()).collect();
+        assert_eq!(ids, vec![4, 3, 5, 6]);
+
+        assert!(!lacks_ids(&drugs));
+        assert!(!assign_missing_ids(&mut drugs, 0));
+        assert_eq!(next_drug_id(&drugs, 0), 7);
+        assert_eq!(next_drug_id(&drugs, 9), 10);
+        assert_eq!(find_drug_by_id(&drugs, 5), Some(2));
+        assert_eq!(find_drug_by_id(&drugs, 1), None);
+    }
+
+    #[test]
+    fn test_patients() {
+        let mut config: Config = toml::from_str(r#"
+            listen_addr = "127.0.0.1:8765"
+            base_url = "http://127.0.0.1:8765/"
+            data_path = "data.json"
+            auth_tokens = ["caregiver", { token = "alice", patients = ["Alice"] }]
+            column_profiles = {}
+
+            [[patients]]
+            name = "Alice"
+            data_path = "alice.json"
+        "#).unwrap();
+        assert_eq!(config.validate_patients(), Ok(()));
+
+        let patients = config.all_patients();
+        let names: Vec<&str> = patients.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec![DEFAULT_PATIENT, "Alice"]);
+        assert_eq!(patients[1].journal_path(), PathBuf::from("alice.json.journal"));
+        assert!(config.auth_tokens[0].may_access(&patients[0]));
+        assert!(!config.auth_tokens[1].may_access(&patients[0]));
+        assert!(config.auth_tokens[1].may_access(&patients[1]));
+        assert_eq!(config.auth_tokens[1].label(), None);
+
+        config.patients[0].name = DEFAULT_PATIENT.to_owned();
+        assert!(config.validate_patients().is_err());
+        config.patients.clear();
+        assert!(config.validate_patients().is_err());
+        config.data_path = None;
+        config.auth_tokens.truncate(1);
+        assert!(config.validate_patients().is_err());
+    }
+
+    #[test]
+    fn test_consumption_persist_interval() {
+        let mut config = Config::new(
+            "127.0.0.1:8765".to_owned(), "http://127.0.0.1:8765/".to_owned(), Some("data.json".to_owned()),
+            Vec::new(), HashMap::new(), None, ReplenishmentThresholds::default(),
+        );
+        assert_eq!(config.consumption_persist_interval(), Ok(None));
+        config.consumption_persist_interval_minutes = Some(0);
+        assert_eq!(config.consumption_persist_interval(), Ok(None));
+        config.consumption_persist_interval_minutes = Some(15);
+        assert_eq!(config.consumption_persist_interval(), Ok(Some(std::time::Duration::from_secs(900))));
+        config.consumption_persist_interval_minutes = Some(u64::MAX);
+        assert!(config.consumption_persist_interval().is_err());
+    }
+
+    #[test]
+    fn test_take_days_weekly() {
+        // 2023-05-01 is a Monday
+        let monday = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        let mut drug = Drug::for_test(1, 0);
+        drug.replenish(&Rational64::from_integer(10));
+        drug.set_frequency(Frequency::Weekdays { weekdays: vec![chrono::Weekday::Wed] });
+
+        assert_eq!(drug.total_dosage_day(), Rational64::new(1, 7));
+        assert_eq!(drug.take_days(2, monday), Ok(Rational64::zero()));
+        assert_eq!(drug.take_days(7, monday), Ok(Rational64::from_integer(-1)));
+        assert_eq!(drug.take_days(14, monday + Duration::days(2)), Ok(Rational64::from_integer(-2)));
+        assert!(drug.take_days(i64::MAX, monday).is_err());
+        assert_eq!(drug.remaining(), Rational64::from_integer(7));
+    }
+
+    #[test]
+    fn test_dosage_plan() {
+        let date = |d: u32| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let dosage = |morning: i64| DailyDosage {
+            morning: Rational64::from_integer(morning),
+            ..DailyDosage::zero()
+        };
+        let mut drug = Drug::for_test(1, 0);
+        drug.replenish(&Rational64::from_integer(10));
+        drug.set_dosage_plan(
+            vec![
+                DosagePhase { start: date(1), end: date(3), dosage: dosage(2) },
+                DosagePhase { start: date(4), end: date(7), dosage: dosage(1) },
+            ],
+            true,
+        );
+
+        assert_eq!(drug.dosage_on(date(2)).total(), Rational64::from_integer(2));
+        assert_eq!(drug.dosage_on(date(8)).total(), Rational64::zero());
+        assert_eq!(drug.remaining_dosage_day(date(6)), Rational64::one());
+        assert_eq!(drug.remaining_dosage_day(date(8)), Rational64::zero());
+        // 10 units cover 2 + 2 + 2 + 1 + 1 + 1 + 1
+        assert_eq!(drug.planned_days_of_stock(date(1)), None);
+        drug.adjust(&Rational64::from_integer(-2));
+        assert_eq!(drug.planned_days_of_stock(date(1)), Some(5));
+
+        assert!(!drug.hide_if_finished(date(7)));
+        assert!(drug.hide_if_finished(date(8)));
+        assert!(!drug.show());
+    }
+
+    #[test]
+    fn test_dosage_plan_ending_late() {
+        // such plans can no longer be saved, but may be part of older data
+        let mut drug = Drug::for_test(1, 10);
+        let dosage = DailyDosage { morning: One::one(), ..DailyDosage::zero() };
+        drug.set_dosage_plan(vec![DosagePhase { start: NaiveDate::MIN, end: NaiveDate::MAX, dosage }], false);
+        let today = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        assert_eq!(drug.remaining_dosage_day(today), Rational64::one());
+        assert_eq!(drug.planned_days_of_stock(today), Some(10));
+    }
+
+    #[test]
+    fn test_needs_replenishment() {
+        let thresholds = ReplenishmentThresholds { lead_time_days: 7, safety_stock_weeks: 2, doctor_visit_days: 0 };
+        let status = |remaining: i64, overrides: ReplenishmentOverrides| {
+            let mut drug = Drug::for_test(1, 0);
+            drug.replenish(&Rational64::from_integer(remaining));
+            drug.set_replenishment(Some(Rational64::from_integer(3)), overrides);
+            let dtd = DrugToDisplay::new(
+                0, drug, Some(remaining), Some(remaining / 7), None, DailyDosage::zero(), None,
+                thresholds.overridden_by(&overrides), None, None, false, Vec::new(), Vec::new(),
+            );
+            dtd.needs_replenishment(&Some(6))
+        };
+        let defaults = ReplenishmentOverrides::default();
+
+        assert_eq!(status(6, defaults), ReplenishmentStatus::Late);
+        assert_eq!(status(20, defaults), ReplenishmentStatus::Should);
+        assert_eq!(status(21, defaults), ReplenishmentStatus::Can);
+        assert_eq!(status(49, defaults), ReplenishmentStatus::DoNot);
+
+        let no_lead_time = ReplenishmentOverrides { lead_time_days: Some(0), ..defaults };
+        assert_eq!(status(6, no_lead_time), ReplenishmentStatus::Should);
+        assert_eq!(status(42, no_lead_time), ReplenishmentStatus::DoNot);
+        // the safety stock in units applies regardless of the weeks
+        assert_eq!(status(3, ReplenishmentOverrides { lead_time_days: Some(0), safety_stock_weeks: Some(0), ..defaults }), ReplenishmentStatus::Should);
+    }
+
+    #[test]
+    fn test_prescription_refills() {
+        let date = |d: u32| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let mut drug = Drug::for_test(1, 0);
+        assert!(drug.use_prescription_refill(date(1)).is_err());
+
+        drug.set_prescription(Some(Prescription::new("Dr. Example".to_owned(), date(1), Some(date(20)), 2)));
+        assert_eq!(drug.prescription().unwrap().warnings(date(1), 7), Vec::<String>::new());
+        assert!(drug.use_prescription_refill(date(2)).is_ok());
+        assert_eq!(drug.prescription().unwrap().warnings(date(14), 7), vec!["last refill", "expires on 2023-05-20"]);
+        assert!(drug.use_prescription_refill(date(21)).is_err());
+        assert!(drug.use_prescription_refill(date(3)).is_ok());
+        assert!(drug.use_prescription_refill(date(4)).is_err());
+
+        drug.restore_prescription_refill();
+        assert_eq!(drug.prescription().unwrap().refills_remaining(), 1);
+    }
+
+    #[test]
+    fn test_batches() {
+        let date = |d: u32| NaiveDate::from_ymd_opt(2023, 5, d).unwrap();
+        let amount = Rational64::from_integer;
+        let mut drug = Drug::for_test(1, 0);
+        drug.add_batch(StockBatch::new(amount(10), Some(date(20)), Some("B".to_owned())));
+        drug.replenish(&amount(5));
+        drug.add_batch(StockBatch::new(amount(10), Some(date(10)), Some("A".to_owned())));
+        assert_eq!(drug.remaining(), amount(25));
+
+        // at 1 per day from the 1st, lot A is used up by the 10th and lot B by the 20th; from the
+        // 6th, half of lot A is left over
+        assert_eq!(drug.unused_at_expiry(date(1), amount(1)), vec![]);
+        assert_eq!(drug.unused_at_expiry(date(6), amount(1)), vec![(date(10), amount(5))]);
+
+        drug.reduce(&amount(12));
+        let lots: Vec<(Option<&str>, Rational64)> = drug.batches().iter()
+            .map(|b| (b.lot(), b.amount()))
+            .collect();
+        assert_eq!(lots, vec![(Some("B"), amount(8)), (None, amount(5))]);
+
+        assert_eq!(drug.remove_newest(&amount(7)), amount(-7));
+        assert_eq!(drug.batches().len(), 1);
+        assert_eq!(drug.remaining(), amount(6));
+
+        assert_eq!(drug.expired_batches(date(20)).len(), 0);
+        assert_eq!(drug.discard_expired(date(21)), amount(-6));
+        assert_eq!(drug.remaining(), amount(0));
+    }
+
+    #[test]
+    fn test_locations() {
+        let amount = Rational64::from_integer;
+        let mut drug = Drug::for_test(1, 0);
+        drug.add_batch(StockBatch::new(amount(10), NaiveDate::from_ymd_opt(2023, 5, 1), None));
+        drug.replenish(&amount(20));
+        assert!(drug.transfer(&amount(31), DEFAULT_LOCATION, "travel").is_err());
+        assert!(drug.transfer(&amount(15), DEFAULT_LOCATION, "travel").is_ok());
+
+        // the batch expiring first has been moved along
+        let travel_expiry: Vec<Option<NaiveDate>> = drug.batches().iter()
+            .filter(|b| b.location() == "travel")
+            .map(|b| b.expiry())
+            .collect();
+        assert_eq!(travel_expiry, vec![NaiveDate::from_ymd_opt(2023, 5, 1), None]);
+
+        // consumption comes from the primary location first
+        drug.reduce(&amount(20));
+        let stocks: Vec<(String, Rational64)> = drug.stock_by_location().iter()
+            .map(|s| (s.location().to_owned(), s.amount()))
+            .collect();
+        assert_eq!(stocks, vec![("travel".to_owned(), amount(10))]);
+        assert_eq!(drug.remaining(), amount(10));
+    }
+
+    #[test]
+    fn test_normalize_batches() {
+        let mut drug = Drug::for_test(1, 0);
+        drug.replenish(&Rational64::from_integer(3));
+        drug.remaining = Rational64::from_integer(5);
+        drug.normalize_batches();
+        assert_eq!(drug.batches().len(), 2);
+        drug.remaining = Rational64::from_integer(1);
+        drug.normalize_batches();
+        assert_eq!(drug.batches().len(), 1);
+        assert_eq!(drug.batches()[0].amount(), Rational64::from_integer(1));
+    }
+
+    #[test]
+    fn test_to_units() {
+        let mut drug = Drug::for_test(1, 0);
+        drug.units_per_package = Rational64::from_integer(28);
+        drug.packages_per_prescription = Rational64::from_integer(3);
+        let two = Rational64::from_integer(2);
+        assert_eq!(drug.to_units(two, QuantityUnit::Units), two);
+        assert_eq!(drug.to_units(two, QuantityUnit::Packages), Rational64::from_integer(56));
+        assert_eq!(drug.to_units(Rational64::new(1, 3), QuantityUnit::Prescriptions), Rational64::from_integer(28));
+    }
+
+    #[test]
+    fn test_consume_until() {
+        let start: DateTime<Utc> = "2023-05-01T08:00:00Z".parse().unwrap();
+        let mut drug = Drug::for_test(1, 0);
+        drug.replenish(&Rational64::from_integer(10));
+
+        // not tracked: nothing happens
+        assert_eq!(drug.consume_until(start + Duration::days(3)), Rational64::zero());
+
+        drug.set_tracks_consumption(true, start);
+        assert_eq!(drug.consume_until(start + Duration::hours(47)), Rational64::from_integer(-1));
+        assert_eq!(drug.remaining(), Rational64::from_integer(9));
+
+        // the remaining hours are kept for the next deduction
+        assert_eq!(drug.consume_until(start + Duration::hours(48)), Rational64::from_integer(-1));
+        assert_eq!(drug.take_days(3, start.date_naive()), Ok(Rational64::zero()));
+        assert_eq!(drug.consume_until(start + Duration::days(30)), Rational64::from_integer(-8));
+        assert_eq!(drug.remaining(), Rational64::zero());
+    }
+
+    #[test]
+    fn test_carry_over_consumption_tracking() {
+        let start: DateTime<Utc> = "2023-05-01T08:00:00Z".parse().unwrap();
+        let edited = start + Duration::hours(30);
+        let mut previous = Drug::for_test(1, 10);
+        previous.set_tracks_consumption(true, start);
+
+        // tracking stays on: the hours since the last deduction are not lost
+        let mut drug = Drug::for_test(1, 10);
+        drug.set_tracks_consumption(true, edited);
+        drug.carry_over_consumption_tracking(&previous);
+        assert_eq!(drug.consume_until(start + Duration::hours(48)), Rational64::from_integer(-2));
+
+        // tracking is switched on: it starts at the time of the edit
+        let mut untracked = Drug::for_test(1, 10);
+        untracked.set_tracks_consumption(false, start);
+        let mut drug = Drug::for_test(1, 10);
+        drug.set_tracks_consumption(true, edited);
+        drug.carry_over_consumption_tracking(&untracked);
+        assert_eq!(drug.consume_until(start + Duration::hours(48)), Rational64::zero());
+
+        // tracking is switched off
+        let mut drug = Drug::for_test(1, 10);
+        drug.set_tracks_consumption(false, edited);
+        drug.carry_over_consumption_tracking(&previous);
+        assert!(!drug.tracks_consumption());
+    }
 }
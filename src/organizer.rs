@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Duration, Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use num_rational::Rational64;
+use num_traits::Zero;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{catch_up_consumption, filters, load_data, respond_400, respond_500, Auth};
+use crate::journal::StockChange;
+use crate::model::Drug;
+
+
+/// The number of days a pill organizer holds.
+pub(crate) const ORGANIZER_DAYS: i64 = 7;
+
+/// The names of the compartments of each day, in order.
+const SLOT_NAMES: [&str; 4] = ["morning", "noon", "evening", "night"];
+
+
+/// The contents of one day of a pill organizer.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct OrganizerDay {
+    date: NaiveDate,
+    morning: Vec<FillItem>,
+    noon: Vec<FillItem>,
+    evening: Vec<FillItem>,
+    night: Vec<FillItem>,
+}
+
+/// The amount of a drug to put into a compartment.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct FillItem {
+    drug_id: u64,
+    trade_name: String,
+    amount: Rational64,
+}
+
+#[derive(Template)]
+#[template(path = "organizer.html", escape = "none")]
+struct OrganizerTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub start: NaiveDate,
+    pub slot_names: [&'static str; 4],
+    pub days: Vec<OrganizerDay>,
+}
+
+
+impl OrganizerDay {
+    pub fn date(&self) -> NaiveDate { self.date }
+
+    /// The contents of the compartment for the given time of day, one of [`SLOT_NAMES`].
+    pub fn compartment(&self, slot: &str) -> &[FillItem] {
+        match slot {
+            "morning" => &self.morning,
+            "noon" => &self.noon,
+            "evening" => &self.evening,
+            _ => &self.night,
+        }
+    }
+}
+
+impl FillItem {
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn amount(&self) -> Rational64 { self.amount }
+}
+
+
+/// Whether a drug goes into the pill organizer.
+fn is_organized(drug: &Drug) -> bool {
+    drug.show() && drug.is_pill() && !drug.as_needed()
+}
+
+/// Lists the contents of each compartment of a pill organizer filled for the week starting on
+/// `start`.
+pub(crate) fn fill_plan(data: &[Drug], start: NaiveDate) -> Vec<OrganizerDay> {
+    let organized: Vec<&Drug> = data.iter()
+        .filter(|drug| is_organized(drug))
+        .collect();
+    (0..ORGANIZER_DAYS)
+        .map_while(|offset| start.checked_add_signed(Duration::days(offset)))
+        .map(|date| {
+            let mut day = OrganizerDay {
+                date,
+                morning: Vec::new(),
+                noon: Vec::new(),
+                evening: Vec::new(),
+                night: Vec::new(),
+            };
+            for drug in &organized {
+                let dosage = drug.dosage_on(date);
+                let slots = [
+                    (dosage.morning, &mut day.morning),
+                    (dosage.noon, &mut day.noon),
+                    (dosage.evening, &mut day.evening),
+                    (dosage.night, &mut day.night),
+                ];
+                for (amount, compartment) in slots {
+                    if amount > Zero::zero() {
+                        compartment.push(FillItem {
+                            drug_id: drug.id(),
+                            trade_name: drug.trade_name().to_owned(),
+                            amount,
+                        });
+                    }
+                }
+            }
+            day
+        })
+        .collect()
+}
+
+/// Deducts the contents of a pill organizer filled for the week starting on `start` from the stock.
+///
+/// Returns the changes to record in the journal.
+pub(crate) fn fill_organizer(data: &mut [Drug], start: NaiveDate) -> Vec<StockChange> {
+    let end = start.checked_add_signed(Duration::days(ORGANIZER_DAYS))
+        .unwrap_or(NaiveDate::MAX);
+    let mut changes = Vec::new();
+    for drug in data.iter_mut().filter(|drug| is_organized(drug)) {
+        let delta = drug.take_between(start, end);
+        if !delta.is_zero() {
+            changes.push(StockChange::new(drug.id(), delta, drug.remaining()));
+        }
+    }
+    changes
+}
+
+/// Parses the first day of the organizer week, defaulting to `today`.
+pub(crate) fn parse_start(start: Option<&str>, today: NaiveDate) -> Result<NaiveDate, &'static str> {
+    let start = match start.map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        None => today,
+        Some(s) => s.parse()
+            .map_err(|_| "invalid value for \"start\"")?,
+    };
+    check_start(start)
+}
+
+/// Checks that the whole organizer week starting on `start` lies within the supported range of
+/// dates.
+pub(crate) fn check_start(start: NaiveDate) -> Result<NaiveDate, &'static str> {
+    match start.checked_add_signed(Duration::days(ORGANIZER_DAYS)) {
+        Some(_) => Ok(start),
+        None => Err("the organizer week ends too late"),
+    }
+}
+
+
+pub(crate) async fn handle_get_organizer(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let now = Utc::now();
+    let start = match parse_start(query_values.get("start").map(|s| s.as_str()), now.with_timezone(&Local).date_naive()) {
+        Ok(s) => s,
+        Err(msg) => return respond_400(msg),
+    };
+    let mut data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    catch_up_consumption(&mut data, now);
+
+    let template = OrganizerTemplate {
+        token,
+        patient: &auth.patient.name,
+        start,
+        slot_names: SLOT_NAMES,
+        days: fill_plan(&data, start),
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Weekday;
+    use num_traits::One;
+
+    use crate::schedule::Frequency;
+    use super::*;
+
+    #[test]
+    fn test_fill_plan() {
+        let mut drug = Drug::for_test(1, 20)
+            .with_dosage(Rational64::new(1, 2), Zero::zero(), Zero::zero(), One::one());
+        drug.set_frequency(Frequency::Weekdays { weekdays: vec![Weekday::Mon, Weekday::Thu] });
+        // 2023-05-01 is a Monday
+        let start = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+
+        let days = fill_plan(std::slice::from_ref(&drug), start);
+        assert_eq!(days.len(), 7);
+        let morning: Vec<usize> = days.iter()
+            .map(|d| d.compartment("morning").len())
+            .collect();
+        assert_eq!(morning, vec![1, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(days[3].compartment("morning")[0].amount(), Rational64::new(1, 2));
+        assert_eq!(days[3].compartment("night")[0].amount(), One::one());
+        assert!(days[0].compartment("noon").is_empty());
+
+        let mut data = vec![drug];
+        let changes = fill_organizer(&mut data, start);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(data[0].remaining(), Rational64::from_integer(17));
+    }
+
+    #[test]
+    fn test_parse_start() {
+        let today = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        assert_eq!(parse_start(None, today), Ok(today));
+        assert_eq!(parse_start(Some(" 2023-05-08 "), today), Ok(today + Duration::days(7)));
+        assert!(parse_start(Some("next week"), today).is_err());
+        assert!(parse_start(Some(&NaiveDate::MAX.to_string()), today).is_err());
+        assert!(check_start(NaiveDate::MAX).is_err());
+
+        // nothing beyond the last date, even if the start has not been checked
+        assert_eq!(fill_plan(&[Drug::for_test(1, 20)], NaiveDate::MAX).len(), 1);
+        let mut data = vec![Drug::for_test(1, 20)];
+        fill_organizer(&mut data, NaiveDate::MAX);
+        assert!(data[0].remaining() >= Rational64::from_integer(19));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Days, Duration, Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use tracing::error;
+
+use crate::{load_data, load_drugs_to_display, respond_400, respond_500, Auth};
+use crate::model::DrugToDisplay;
+
+
+/// The number of days to plan ahead if not specified otherwise.
+pub(crate) const DEFAULT_HORIZON_DAYS: i64 = 180;
+
+/// The maximum number of days that can be planned ahead.
+pub(crate) const MAX_HORIZON_DAYS: i64 = 5 * 366;
+
+
+/// A proposed doctor visit and the prescriptions to renew during it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct PlannedVisit {
+    date: NaiveDate,
+    /// Whether at least one of the drugs should have been renewed before today already.
+    overdue: bool,
+    renewals: Vec<PlannedRenewal>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct PlannedRenewal {
+    drug_id: u64,
+    trade_name: String,
+    /// The last day on which the prescription could be renewed while keeping the safety stock.
+    deadline: NaiveDate,
+    /// The number of prescriptions required to get back above the safety stock; usually 1.
+    prescriptions: u32,
+}
+
+/// A drug whose prescription has to be renewed regularly.
+#[derive(Clone, Debug)]
+struct Renewable {
+    drug_id: u64,
+    trade_name: String,
+    deadline: NaiveDate,
+    prescription_days: i64,
+}
+
+#[derive(Template)]
+#[template(path = "planner.html", escape = "none")]
+struct PlannerTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub horizon_days: i64,
+    pub visits: Vec<PlannedVisit>,
+}
+
+
+impl PlannedVisit {
+    pub fn date(&self) -> NaiveDate { self.date }
+    pub fn overdue(&self) -> bool { self.overdue }
+    pub fn renewals(&self) -> &[PlannedRenewal] { &self.renewals }
+}
+
+impl PlannedRenewal {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn deadline(&self) -> NaiveDate { self.deadline }
+    pub fn prescriptions(&self) -> u32 { self.prescriptions }
+}
+
+impl Renewable {
+    fn from_display(dtd: &DrugToDisplay, today: NaiveDate) -> Option<Self> {
+        if !dtd.drug.show() || !dtd.drug.in_replenishment_cycle() || dtd.drug.as_needed() {
+            return None;
+        }
+        let remaining_days = dtd.remaining_days?;
+        let prescription_days = dtd.weeks_per_prescription? * 7;
+        if prescription_days <= 0 {
+            return None;
+        }
+
+        // the visit itself is planned, so only the time to get the drug from the pharmacy counts
+        let mut lead_time_days = dtd.thresholds.lead_time_days;
+        if dtd.needs_doctor_visit {
+            lead_time_days -= dtd.thresholds.doctor_visit_days;
+        }
+        let days_until_deadline = remaining_days - lead_time_days - 7*dtd.thresholds.safety_stock_weeks;
+        let deadline = today.checked_add_signed(Duration::days(days_until_deadline))?;
+
+        Some(Self {
+            drug_id: dtd.drug.id(),
+            trade_name: dtd.drug.trade_name().to_owned(),
+            deadline,
+            prescription_days,
+        })
+    }
+}
+
+
+/// Parses the number of days to plan ahead, falling back to the default if it is not given.
+pub(crate) fn parse_horizon_days(value: Option<&str>) -> Result<i64, String> {
+    let days_str = match value {
+        None => return Ok(DEFAULT_HORIZON_DAYS),
+        Some(ds) => ds,
+    };
+    match days_str.parse() {
+        Ok(d) if d > 0 && d <= MAX_HORIZON_DAYS => Ok(d),
+        _ => Err(format!("\"days\" must be between 1 and {}", MAX_HORIZON_DAYS)),
+    }
+}
+
+/// Proposes doctor visits until `today + horizon_days` that renew the prescriptions of all drugs in
+/// the replenishment cycle in time.
+///
+/// Each visit takes place on the earliest deadline of any drug and renews the prescriptions of all
+/// drugs that would otherwise need another visit before the shortest-lasting prescription runs
+/// out, so that visits are as few and far between as possible.
+///
+/// Fails if the end of the horizon lies beyond the supported range of dates.
+pub(crate) fn plan_visits(drugs: &[DrugToDisplay], today: NaiveDate, horizon_days: i64) -> Result<Vec<PlannedVisit>, &'static str> {
+    let horizon_end = u64::try_from(horizon_days).ok()
+        .and_then(|d| today.checked_add_days(Days::new(d)))
+        .ok_or("date out of range")?;
+    let renewables = drugs.iter()
+        .filter_map(|dtd| Renewable::from_display(dtd, today))
+        .collect();
+    Ok(plan_renewals(renewables, today, horizon_end))
+}
+
+fn plan_renewals(mut renewables: Vec<Renewable>, today: NaiveDate, horizon_end: NaiveDate) -> Vec<PlannedVisit> {
+    let batch_days = match renewables.iter().map(|r| r.prescription_days).min() {
+        Some(bd) => bd,
+        None => return Vec::new(),
+    };
+
+    let mut visits = Vec::new();
+    while let Some(earliest_deadline) = renewables.iter().map(|r| r.deadline).min() {
+        if earliest_deadline > horizon_end {
+            break;
+        }
+
+        let date = earliest_deadline.max(today);
+        let batch_end = date + Duration::days(batch_days);
+        let mut renewals = Vec::new();
+        for renewable in &mut renewables {
+            if renewable.deadline >= batch_end {
+                continue;
+            }
+            let deadline = renewable.deadline;
+            let mut prescriptions = 0;
+            while renewable.deadline < batch_end {
+                renewable.deadline += Duration::days(renewable.prescription_days);
+                prescriptions += 1;
+            }
+            renewals.push(PlannedRenewal {
+                drug_id: renewable.drug_id,
+                trade_name: renewable.trade_name.clone(),
+                deadline,
+                prescriptions,
+            });
+        }
+        renewals.sort_by_key(|r| r.deadline);
+
+        visits.push(PlannedVisit {
+            date,
+            overdue: earliest_deadline < today,
+            renewals,
+        });
+    }
+    visits
+}
+
+
+pub(crate) async fn handle_get_planner(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+    let horizon_days = match parse_horizon_days(query_values.get("days").map(|d| d.as_str())) {
+        Ok(hd) => hd,
+        Err(e) => return respond_400(&e),
+    };
+
+    let data = match load_data(&auth.patient).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    let now = Utc::now();
+    let drugs = match load_drugs_to_display(&auth.patient, &data, now).await {
+        None => return respond_500(),
+        Some(d) => d,
+    };
+    let visits = match plan_visits(&drugs, now.with_timezone(&Local).date_naive(), horizon_days) {
+        Ok(v) => v,
+        Err(e) => return respond_400(e),
+    };
+
+    let template = PlannerTemplate {
+        token,
+        patient: &auth.patient.name,
+        horizon_days,
+        visits,
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, m, d).unwrap()
+    }
+
+    fn renewable(drug_id: u64, deadline: NaiveDate, prescription_days: i64) -> Renewable {
+        Renewable {
+            drug_id,
+            trade_name: format!("drug {}", drug_id),
+            deadline,
+            prescription_days,
+        }
+    }
+
+    #[test]
+    fn test_plan_renewals() {
+        let renewables = vec![
+            renewable(1, date(1, 10), 28),
+            renewable(2, date(1, 20), 56),
+            renewable(3, date(3, 1), 84),
+        ];
+        let visits = plan_renewals(renewables, date(1, 1), date(3, 31));
+        let summary: Vec<(NaiveDate, Vec<u64>)> = visits.iter()
+            .map(|v| (v.date(), v.renewals().iter().map(|r| r.drug_id()).collect()))
+            .collect();
+        assert_eq!(summary, vec![
+            // drug 2 would need another visit before Feb 7, so it is renewed along with drug 1
+            (date(1, 10), vec![1, 2]),
+            (date(2, 7), vec![1, 3]),
+            (date(3, 7), vec![1, 2]),
+        ]);
+    }
+
+    #[test]
+    fn test_plan_renewals_overdue() {
+        let renewables = vec![renewable(1, date(1, 1), 14)];
+        let visits = plan_renewals(renewables, date(1, 20), date(1, 31));
+        assert_eq!(visits.len(), 1);
+        assert_eq!(visits[0].date(), date(1, 20));
+        assert!(visits[0].overdue());
+        assert_eq!(visits[0].renewals()[0].prescriptions(), 3);
+    }
+
+    #[test]
+    fn test_parse_horizon_days() {
+        assert_eq!(parse_horizon_days(None), Ok(DEFAULT_HORIZON_DAYS));
+        assert_eq!(parse_horizon_days(Some("90")), Ok(90));
+        assert!(parse_horizon_days(Some("0")).is_err());
+        assert!(parse_horizon_days(Some("9223372036854775807")).is_err());
+        assert!(parse_horizon_days(Some("soon")).is_err());
+        assert!(plan_visits(&[], date(1, 1), i64::MAX).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::fmt;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use num_rational::Rational64;
+use num_traits::{One, Zero};
+use serde::{Deserialize, Serialize};
+
+
+/// The maximum number of days, about ten years, that a dosage plan may last.
+pub(crate) const MAX_DOSAGE_PLAN_DAYS: i64 = 3653;
+
+
+/// The days on which a drug's dosage is taken.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub(crate) enum Frequency {
+    /// Every day.
+    #[default]
+    Daily,
+
+    /// On specific days of the week; a single weekday means once a week.
+    Weekdays {
+        weekdays: Vec<Weekday>,
+    },
+
+    /// Every `interval` days, counting from `start`.
+    EveryNDays {
+        interval: u32,
+        start: NaiveDate,
+    },
+
+    /// Once a month on the given day, or on the last day of the month in shorter months.
+    Monthly {
+        day: u32,
+    },
+}
+
+/// The amounts taken at each time of day.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct DailyDosage {
+    pub morning: Rational64,
+    pub noon: Rational64,
+    pub evening: Rational64,
+    pub night: Rational64,
+}
+
+/// A time of day at which a dose is taken.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DoseSlot {
+    Morning,
+    Noon,
+    Evening,
+    Night,
+}
+
+/// A period of a dosage plan during which a fixed dosage is taken, e.g. one step of a taper.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub(crate) struct DosagePhase {
+    /// First day of the phase.
+    pub start: NaiveDate,
+    /// Last day of the phase (inclusive).
+    pub end: NaiveDate,
+    #[serde(flatten)] pub dosage: DailyDosage,
+}
+
+
+impl Frequency {
+    /// Whether the dosage is taken on the given date.
+    pub fn applies_on(&self, date: NaiveDate) -> bool {
+        match self {
+            Self::Daily => true,
+            Self::Weekdays { weekdays } => weekdays.contains(&date.weekday()),
+            Self::EveryNDays { interval, start } => {
+                if *interval == 0 {
+                    return false;
+                }
+                let days_since_start = (date - *start).num_days();
+                days_since_start >= 0 && days_since_start % i64::from(*interval) == 0
+            },
+            Self::Monthly { day } => {
+                let last_day = last_day_of_month(date);
+                date.day() == (*day).clamp(1, last_day)
+            },
+        }
+    }
+
+    /// The average number of times per day the dosage is taken.
+    pub fn average_per_day(&self) -> Rational64 {
+        match self {
+            Self::Daily => One::one(),
+            Self::Weekdays { weekdays } => {
+                let mut distinct = weekdays.clone();
+                distinct.sort_by_key(|wd| wd.num_days_from_monday());
+                distinct.dedup();
+                Rational64::new(distinct.len().try_into().unwrap(), 7)
+            },
+            Self::EveryNDays { interval, .. } => if *interval == 0 {
+                Zero::zero()
+            } else {
+                Rational64::new(1, (*interval).into())
+            },
+            // 12 times in an average Gregorian year of 365.25 days
+            Self::Monthly { .. } => Rational64::new(48, 1461),
+        }
+    }
+
+    pub fn is_daily(&self) -> bool {
+        matches!(self, Self::Daily)
+    }
+}
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Weekdays { weekdays } => {
+                let mut sorted = weekdays.clone();
+                sorted.sort_by_key(|wd| wd.num_days_from_monday());
+                sorted.dedup();
+                let names: Vec<String> = sorted.iter()
+                    .map(|wd| wd.to_string())
+                    .collect();
+                write!(f, "on {}", names.join(", "))
+            },
+            Self::EveryNDays { interval, start } => write!(f, "every {} days from {}", interval, start),
+            Self::Monthly { day } => write!(f, "monthly on day {}", day),
+        }
+    }
+}
+
+impl DoseSlot {
+    pub const ALL: [DoseSlot; 4] = [Self::Morning, Self::Noon, Self::Evening, Self::Night];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Morning => "morning",
+            Self::Noon => "noon",
+            Self::Evening => "evening",
+            Self::Night => "night",
+        }
+    }
+
+    pub fn from_form_value(value: &str) -> Option<Self> {
+        Self::ALL.into_iter()
+            .find(|slot| slot.as_str() == value)
+    }
+}
+
+impl fmt::Display for DoseSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl DailyDosage {
+    pub fn zero() -> Self {
+        Self {
+            morning: Zero::zero(),
+            noon: Zero::zero(),
+            evening: Zero::zero(),
+            night: Zero::zero(),
+        }
+    }
+
+    pub fn morning(&self) -> Rational64 { self.morning }
+    pub fn noon(&self) -> Rational64 { self.noon }
+    pub fn evening(&self) -> Rational64 { self.evening }
+    pub fn night(&self) -> Rational64 { self.night }
+
+    pub fn total(&self) -> Rational64 {
+        self.morning + self.noon + self.evening + self.night
+    }
+
+    /// The amount taken at the given time of day.
+    pub fn in_slot(&self, slot: DoseSlot) -> Rational64 {
+        match slot {
+            DoseSlot::Morning => self.morning,
+            DoseSlot::Noon => self.noon,
+            DoseSlot::Evening => self.evening,
+            DoseSlot::Night => self.night,
+        }
+    }
+}
+
+impl DosagePhase {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.start <= date && date <= self.end
+    }
+}
+
+
+/// Checks that each phase of a dosage plan ends no earlier than it starts and at most
+/// [`MAX_DOSAGE_PLAN_DAYS`] after the start of the plan, and that no two phases overlap.
+pub(crate) fn validate_dosage_plan(phases: &[DosagePhase]) -> Result<(), String> {
+    let plan_start = match phases.iter().map(|p| p.start).min() {
+        Some(ps) => ps,
+        None => return Ok(()),
+    };
+    for phase in phases {
+        if phase.end < phase.start {
+            return Err(format!("dosage phase starting {} ends before it starts", phase.start));
+        }
+        if (phase.end - plan_start).num_days() >= MAX_DOSAGE_PLAN_DAYS {
+            return Err(format!("dosage plan must not last longer than {} days", MAX_DOSAGE_PLAN_DAYS));
+        }
+    }
+    let mut sorted: Vec<&DosagePhase> = phases.iter().collect();
+    sorted.sort_by_key(|p| p.start);
+    for pair in sorted.windows(2) {
+        if pair[1].start <= pair[0].end {
+            return Err(format!("dosage phases starting {} and {} overlap", pair[0].start, pair[1].start));
+        }
+    }
+    Ok(())
+}
+
+fn last_day_of_month(date: NaiveDate) -> u32 {
+    let (next_year, next_month) = if date.month() == 12 {
+        (date.year() + 1, 1)
+    } else {
+        (date.year(), date.month() + 1)
+    };
+    NaiveDate::from_ymd_opt(next_year, next_month, 1)
+        .and_then(|first_of_next| first_of_next.pred_opt())
+        .map(|last| last.day())
+        .unwrap_or(28)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, Weekday};
+    use num_rational::Rational64;
+    use super::{validate_dosage_plan, DailyDosage, DosagePhase, Frequency};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_weekdays() {
+        let freq = Frequency::Weekdays { weekdays: vec![Weekday::Mon, Weekday::Wed, Weekday::Fri] };
+        // 2023-05-01 is a Monday
+        let applies: Vec<bool> = (1..=7)
+            .map(|d| freq.applies_on(date(2023, 5, d)))
+            .collect();
+        assert_eq!(applies, vec![true, false, true, false, true, false, false]);
+        assert_eq!(freq.average_per_day(), Rational64::new(3, 7));
+    }
+
+    #[test]
+    fn test_every_n_days() {
+        let freq = Frequency::EveryNDays { interval: 2, start: date(2023, 5, 2) };
+        assert!(!freq.applies_on(date(2023, 4, 30)));
+        assert!(!freq.applies_on(date(2023, 5, 1)));
+        assert!(freq.applies_on(date(2023, 5, 2)));
+        assert!(!freq.applies_on(date(2023, 5, 3)));
+        assert!(freq.applies_on(date(2023, 5, 4)));
+        assert_eq!(freq.average_per_day(), Rational64::new(1, 2));
+    }
+
+    #[test]
+    fn test_monthly() {
+        let freq = Frequency::Monthly { day: 31 };
+        assert!(freq.applies_on(date(2023, 1, 31)));
+        assert!(freq.applies_on(date(2023, 2, 28)));
+        assert!(!freq.applies_on(date(2024, 2, 28)));
+        assert!(freq.applies_on(date(2024, 2, 29)));
+        assert!(freq.applies_on(date(2023, 12, 31)));
+        assert!(!freq.applies_on(date(2023, 12, 1)));
+    }
+
+    #[test]
+    fn test_validate_dosage_plan() {
+        let phase = |start: NaiveDate, end: NaiveDate| DosagePhase {
+            start,
+            end,
+            dosage: DailyDosage::zero(),
+        };
+        assert!(validate_dosage_plan(&[]).is_ok());
+        assert!(validate_dosage_plan(&[
+            phase(date(2023, 5, 8), date(2023, 5, 14)),
+            phase(date(2023, 5, 1), date(2023, 5, 7)),
+        ]).is_ok());
+        assert!(validate_dosage_plan(&[phase(date(2023, 5, 2), date(2023, 5, 1))]).is_err());
+        assert!(validate_dosage_plan(&[
+            phase(date(2023, 5, 1), date(2023, 5, 7)),
+            phase(date(2023, 5, 7), date(2023, 5, 14)),
+        ]).is_err());
+
+        // at most ten years from the first day
+        assert!(validate_dosage_plan(&[phase(date(2023, 5, 1), date(2033, 4, 30))]).is_ok());
+        assert!(validate_dosage_plan(&[phase(date(2023, 5, 1), date(2033, 5, 1))]).is_err());
+        assert!(validate_dosage_plan(&[
+            phase(date(2023, 5, 1), date(2023, 5, 7)),
+            phase(date(2040, 5, 1), date(2040, 5, 7)),
+        ]).is_err());
+        assert!(validate_dosage_plan(&[phase(date(2023, 5, 1), NaiveDate::MAX)]).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use askama::Template;
+use chrono::{Duration, Local, NaiveDate, Utc};
+use hyper::{Body, Request, Response};
+use num_rational::Rational64;
+use num_traits::Zero;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{catch_up_consumption, filters, load_data, respond_400, respond_500, Auth};
+use crate::journal::StockChange;
+use crate::model::Drug;
+use crate::schedule::DailyDosage;
+
+
+/// The storage location packed drugs are moved to if not specified otherwise.
+pub(crate) const DEFAULT_TRAVEL_LOCATION: &str = "travel";
+
+/// The maximum number of days, including buffer days, that can be packed for.
+pub(crate) const MAX_TRIP_DAYS: i64 = 366;
+
+
+/// A trip for which drugs are to be packed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Trip {
+    start: NaiveDate,
+    /// Last day of the trip (inclusive).
+    end: NaiveDate,
+    /// Additional days to pack for in case the trip takes longer.
+    buffer_days: i64,
+    /// The day after the last day to pack for.
+    pack_end: NaiveDate,
+}
+
+/// The amount of a drug to pack for a trip.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct PackingItem {
+    drug_id: u64,
+    trade_name: String,
+    /// The amounts to pack for each time of day.
+    dosage: DailyDosage,
+    amount: Rational64,
+    /// The stock projected to be left when the trip starts.
+    available: Rational64,
+    /// Whether the stock does not suffice for the trip.
+    insufficient: bool,
+}
+
+#[derive(Template)]
+#[template(path = "travel.html", escape = "none")]
+struct TravelTemplate<'a> {
+    pub token: &'a str,
+    pub patient: &'a str,
+    pub start: String,
+    pub end: String,
+    pub buffer_days: i64,
+    pub items: Option<Vec<PackingItem>>,
+    pub location: &'a str,
+}
+
+
+impl Trip {
+    pub fn new(start: NaiveDate, end: NaiveDate, buffer_days: i64) -> Result<Self, String> {
+        if end < start {
+            return Err("the trip ends before it starts".to_owned());
+        }
+        if buffer_days < 0 {
+            return Err("the number of buffer days must not be negative".to_owned());
+        }
+        if buffer_days > MAX_TRIP_DAYS || (end - start).num_days() + 1 + buffer_days > MAX_TRIP_DAYS {
+            return Err(format!("cannot pack for more than {} days", MAX_TRIP_DAYS));
+        }
+        let pack_end = end.checked_add_signed(Duration::days(1 + buffer_days))
+            .ok_or("the trip ends too late")?;
+        Ok(Self {
+            start,
+            end,
+            buffer_days,
+            pack_end,
+        })
+    }
+
+    /// Parses a trip from the textual values of its start date, end date and buffer days.
+    pub fn parse(start: Option<&str>, end: Option<&str>, buffer_days: Option<&str>) -> Result<Self, String> {
+        let start: NaiveDate = start
+            .ok_or("missing value for \"start\"")?
+            .trim()
+            .parse()
+            .map_err(|_| "invalid value for \"start\"")?;
+        let end: NaiveDate = end
+            .ok_or("missing value for \"end\"")?
+            .trim()
+            .parse()
+            .map_err(|_| "invalid value for \"end\"")?;
+        let buffer_days: i64 = match buffer_days.map(|bd| bd.trim()).filter(|bd| !bd.is_empty()) {
+            None => 0,
+            Some(bd) => bd.parse()
+                .map_err(|_| "invalid number of buffer days")?,
+        };
+        Self::new(start, end, buffer_days)
+    }
+
+    /// Parses a trip from the `start`, `end` and `buffer-days` values of a form or query string.
+    pub fn from_values(values: &HashMap<String, String>) -> Result<Self, String> {
+        Self::parse(
+            values.get("start").map(|s| s.as_str()),
+            values.get("end").map(|s| s.as_str()),
+            values.get("buffer-days").map(|s| s.as_str()),
+        )
+    }
+
+    /// The day after the last day to pack for.
+    fn pack_end(&self) -> NaiveDate { self.pack_end }
+}
+
+impl PackingItem {
+    pub fn drug_id(&self) -> u64 { self.drug_id }
+    pub fn trade_name(&self) -> &str { &self.trade_name }
+    pub fn dosage(&self) -> &DailyDosage { &self.dosage }
+    pub fn amount(&self) -> Rational64 { self.amount }
+    pub fn available(&self) -> Rational64 { self.available }
+    pub fn insufficient(&self) -> bool { self.insufficient }
+}
+
+
+/// Calculates the amount of each shown drug to pack for `trip`.
+///
+/// Drugs taken as needed are not included since their consumption cannot be predicted.
+pub(crate) fn packing_list(data: &[Drug], today: NaiveDate, trip: &Trip) -> Vec<PackingItem> {
+    data.iter()
+        .filter(|drug| drug.show() && !drug.as_needed())
+        .filter_map(|drug| {
+            let dosage = drug.slot_consumption_between(trip.start, trip.pack_end());
+            let amount = dosage.total();
+            if amount.is_zero() {
+                return None;
+            }
+            let available = (drug.remaining() - drug.consumption_between(today, trip.start))
+                .max(Zero::zero());
+            Some(PackingItem {
+                drug_id: drug.id(),
+                trade_name: drug.trade_name().to_owned(),
+                dosage,
+                amount,
+                available,
+                insufficient: available < amount,
+            })
+        })
+        .collect()
+}
+
+/// Moves the amounts to pack for `trip` from the primary location of each drug to `location`, as
+/// far as the stock there suffices.
+///
+/// Returns the changes to record in the journal.
+pub(crate) fn pack_trip(data: &mut [Drug], today: NaiveDate, trip: &Trip, location: &str) -> Vec<StockChange> {
+    let items = packing_list(data, today, trip);
+    let mut changes = Vec::new();
+    for drug in data.iter_mut() {
+        let item = match items.iter().find(|i| i.drug_id == drug.id()) {
+            Some(i) => i,
+            None => continue,
+        };
+        let primary_location = drug.primary_location().to_owned();
+        let amount = item.amount.min(drug.stock_at(&primary_location));
+        if amount.is_zero() {
+            continue;
+        }
+        if drug.transfer(&amount, &primary_location, location).is_ok() {
+            changes.push(StockChange::new(drug.id(), Zero::zero(), drug.remaining()));
+        }
+    }
+    changes
+}
+
+
+pub(crate) async fn handle_get_travel(request: Request<Body>, auth: &Auth) -> Result<Response<Body>, Infallible> {
+    let query_values: HashMap<String, String> = if let Some(query_str) = request.uri().query() {
+        form_urlencoded::parse(query_str.as_bytes())
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let token = query_values.get("token")
+        .map(|t| t.as_str())
+        .unwrap_or("");
+
+    let now = Utc::now();
+    let today = now.with_timezone(&Local).date_naive();
+    let trip = if query_values.contains_key("start") || query_values.contains_key("end") {
+        match Trip::from_values(&query_values) {
+            Ok(t) => Some(t),
+            Err(msg) => return respond_400(&msg),
+        }
+    } else {
+        None
+    };
+
+    let items = match trip {
+        Some(trip) => {
+            let mut data = match load_data(&auth.patient).await {
+                None => return respond_500(),
+                Some(d) => d,
+            };
+            catch_up_consumption(&mut data, now);
+            Some(packing_list(&data, today, &trip))
+        },
+        None => None,
+    };
+
+    let template = TravelTemplate {
+        token,
+        patient: &auth.patient.name,
+        start: trip.map(|t| t.start).unwrap_or(today).to_string(),
+        end: trip.map(|t| t.end).unwrap_or(today).to_string(),
+        buffer_days: trip.map(|t| t.buffer_days).unwrap_or(0),
+        items,
+        location: DEFAULT_TRAVEL_LOCATION,
+    };
+    let body_str = template.render()
+        .expect("failed to render template");
+
+    let resp_res = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(body_str));
+    match resp_res {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!("failed to assemble response body: {}", e);
+            respond_500()
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 5, d).unwrap()
+    }
+
+    #[test]
+    fn test_trip_parse() {
+        assert_eq!(
+            Trip::parse(Some("2023-05-10"), Some("2023-05-12"), None),
+            Trip::new(date(10), date(12), 0),
+        );
+        assert!(Trip::parse(Some("2023-05-12"), Some("2023-05-10"), None).is_err());
+        assert!(Trip::parse(Some("2023-05-10"), None, None).is_err());
+        assert!(Trip::parse(Some("2023-05-10"), Some("2023-05-12"), Some("-1")).is_err());
+        assert_eq!(Trip::new(date(10), date(12), 2).unwrap().pack_end(), date(15));
+        assert!(Trip::new(date(10), date(12), i64::MAX).is_err());
+        assert!(Trip::new(date(10), date(12), MAX_TRIP_DAYS - 2).is_err());
+        assert!(Trip::new(date(10), date(12), MAX_TRIP_DAYS - 3).is_ok());
+        assert!(Trip::new(date(10), NaiveDate::MAX, 0).is_err());
+        assert!(Trip::new(NaiveDate::MAX, NaiveDate::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn test_packing_list() {
+        let drug = Drug::for_test(1, 5)
+            .with_dosage(Rational64::from_integer(1), Zero::zero(), Rational64::new(1, 2), Zero::zero());
+        let trip = Trip::new(date(3), date(4), 1).unwrap();
+        let items = packing_list(&[drug], date(1), &trip);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].dosage().morning(), Rational64::from_integer(3));
+        assert_eq!(items[0].dosage().evening(), Rational64::new(3, 2));
+        assert_eq!(items[0].amount(), Rational64::new(9, 2));
+        // 1.5 per day are taken on the 1st and 2nd before leaving
+        assert_eq!(items[0].available(), Rational64::from_integer(2));
+        assert!(items[0].insufficient());
+    }
+}
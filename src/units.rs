@@ -0,0 +1,236 @@
+use std::fmt;
+
+use num_rational::Rational64;
+
+use crate::filters;
+use crate::util::parse_amount;
+
+
+/// Units that count pieces of a drug rather than measure an active ingredient; amounts given in them
+/// are kept as entered and never converted.
+pub(crate) const COUNT_UNITS: [&str; 5] = ["tablets", "capsules", "drops", "puffs", "sprays"];
+
+
+/// What a unit measures; only amounts of the same dimension can be converted into each other.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Dimension {
+    Mass,
+    Volume,
+    InternationalUnits,
+    Percent,
+    MassConcentration,
+}
+
+/// A unit in which the amount of an active ingredient is given.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Unit {
+    Microgram,
+    Milligram,
+    Gram,
+    Milliliter,
+    Liter,
+    InternationalUnits,
+    Percent,
+    MicrogramPerMilliliter,
+    MilligramPerMilliliter,
+}
+
+/// An amount along with its unit.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Quantity {
+    pub amount: Rational64,
+    pub unit: Unit,
+}
+
+
+impl Dimension {
+    /// The unit into which amounts of this dimension are converted to compare or add them up.
+    pub fn base_unit(&self) -> Unit {
+        match self {
+            Self::Mass => Unit::Milligram,
+            Self::Volume => Unit::Milliliter,
+            Self::InternationalUnits => Unit::InternationalUnits,
+            Self::Percent => Unit::Percent,
+            Self::MassConcentration => Unit::MilligramPerMilliliter,
+        }
+    }
+}
+
+impl Unit {
+    pub const ALL: [Unit; 9] = [
+        Self::Microgram, Self::Milligram, Self::Gram, Self::Milliliter, Self::Liter,
+        Self::InternationalUnits, Self::Percent, Self::MicrogramPerMilliliter,
+        Self::MilligramPerMilliliter,
+    ];
+
+    /// Recognizes a unit by its symbol or one of its common spellings, ignoring case and spaces.
+    pub fn parse(text: &str) -> Option<Self> {
+        let normalized: String = text.chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(|c| c.to_lowercase())
+            // micro sign and Greek small letter mu
+            .map(|c| if c == '\u{3BC}' { '\u{B5}' } else { c })
+            .collect();
+        match normalized.as_str() {
+            "\u{B5}g" | "mcg" | "ug" => Some(Self::Microgram),
+            "mg" => Some(Self::Milligram),
+            "g" => Some(Self::Gram),
+            "ml" => Some(Self::Milliliter),
+            "l" => Some(Self::Liter),
+            "iu" | "i.u." | "ie" | "i.e." => Some(Self::InternationalUnits),
+            "%" => Some(Self::Percent),
+            "\u{B5}g/ml" | "mcg/ml" | "ug/ml" => Some(Self::MicrogramPerMilliliter),
+            "mg/ml" | "g/l" => Some(Self::MilligramPerMilliliter),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Microgram => "\u{B5}g",
+            Self::Milligram => "mg",
+            Self::Gram => "g",
+            Self::Milliliter => "ml",
+            Self::Liter => "l",
+            Self::InternationalUnits => "IU",
+            Self::Percent => "%",
+            Self::MicrogramPerMilliliter => "\u{B5}g/ml",
+            Self::MilligramPerMilliliter => "mg/ml",
+        }
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        match self {
+            Self::Microgram | Self::Milligram | Self::Gram => Dimension::Mass,
+            Self::Milliliter | Self::Liter => Dimension::Volume,
+            Self::InternationalUnits => Dimension::InternationalUnits,
+            Self::Percent => Dimension::Percent,
+            Self::MicrogramPerMilliliter | Self::MilligramPerMilliliter => Dimension::MassConcentration,
+        }
+    }
+
+    /// How many of the base unit of the dimension make up one of this unit.
+    fn base_factor(&self) -> Rational64 {
+        match self {
+            Self::Microgram | Self::MicrogramPerMilliliter => Rational64::new(1, 1000),
+            Self::Gram | Self::Liter => Rational64::from_integer(1000),
+            Self::Milligram | Self::Milliliter | Self::InternationalUnits | Self::Percent
+                | Self::MilligramPerMilliliter => Rational64::from_integer(1),
+        }
+    }
+
+    /// A list of the accepted unit symbols, for error messages.
+    pub fn symbols() -> String {
+        let symbols: Vec<&str> = Self::ALL.iter()
+            .map(|u| u.symbol())
+            .collect();
+        symbols.join(", ")
+    }
+}
+
+/// Recognizes one of the [`COUNT_UNITS`], ignoring case and surrounding spaces.
+pub(crate) fn parse_count_unit(text: &str) -> Option<&'static str> {
+    let text = text.trim();
+    COUNT_UNITS.iter()
+        .copied()
+        .find(|unit| unit.eq_ignore_ascii_case(text))
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+impl Quantity {
+    pub fn new(amount: Rational64, unit: Unit) -> Self {
+        Self { amount, unit }
+    }
+
+    /// Parses an amount followed by a unit, such as "4 g" or "0,5mg".
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let unit_start = text
+            .find(|c: char| c.is_alphabetic() || c == '%' || c == '\u{B5}')
+            .ok_or_else(|| format!("{:?} lacks a unit", text))?;
+        let (amount_str, unit_str) = text.split_at(unit_start);
+        let amount = parse_amount(amount_str)
+            .map_err(|e| format!("invalid amount in {:?}: {}", text, e))?;
+        let unit = Unit::parse(unit_str)
+            .ok_or_else(|| format!("unknown unit {:?}; known units are: {}", unit_str.trim(), Unit::symbols()))?;
+        Ok(Self { amount, unit })
+    }
+
+    /// Converts the quantity into `unit`, or returns `None` if `unit` measures something else.
+    pub fn convert_to(&self, unit: Unit) -> Option<Self> {
+        if self.unit.dimension() != unit.dimension() {
+            return None;
+        }
+        let amount = self.amount * self.unit.base_factor() / unit.base_factor();
+        Some(Self { amount, unit })
+    }
+
+    /// Converts the quantity into the base unit of its dimension.
+    pub fn to_base(self) -> Self {
+        self.convert_to(self.unit.dimension().base_unit())
+            .expect("base unit has a different dimension")
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let amount = filters::frac2amount(self.amount)
+            .map_err(|_| fmt::Error)?;
+        if self.unit == Unit::Percent {
+            write!(f, "{}{}", amount, self.unit)
+        } else {
+            write!(f, "{} {}", amount, self.unit)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num_rational::Rational64;
+    use super::{parse_count_unit, Quantity, Unit};
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(Unit::parse("mcg"), Some(Unit::Microgram));
+        assert_eq!(Unit::parse("\u{3BC}g"), Some(Unit::Microgram));
+        assert_eq!(Unit::parse(" MG "), Some(Unit::Milligram));
+        assert_eq!(Unit::parse("mg / ml"), Some(Unit::MilligramPerMilliliter));
+        assert_eq!(Unit::parse("I.E."), Some(Unit::InternationalUnits));
+        assert_eq!(Unit::parse("tablets"), None);
+        assert_eq!(Unit::parse(""), None);
+
+        assert_eq!(parse_count_unit(" Tablets "), Some("tablets"));
+        assert_eq!(parse_count_unit("mg"), None);
+        assert_eq!(parse_count_unit(""), None);
+    }
+
+    #[test]
+    fn test_convert() {
+        let half_gram = Quantity::parse("0,5 g").unwrap();
+        assert_eq!(half_gram.to_base(), Quantity::new(Rational64::from_integer(500), Unit::Milligram));
+        assert_eq!(
+            half_gram.convert_to(Unit::Microgram),
+            Some(Quantity::new(Rational64::from_integer(500_000), Unit::Microgram)),
+        );
+        assert_eq!(half_gram.convert_to(Unit::Milliliter), None);
+        assert_eq!(
+            Quantity::parse("20mcg/ml").unwrap().to_base(),
+            Quantity::new(Rational64::new(1, 50), Unit::MilligramPerMilliliter),
+        );
+        assert!(Quantity::parse("500").is_err());
+        assert!(Quantity::parse("500 tablets").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Quantity::new(Rational64::new(25, 2), Unit::Milligram).to_string(), "12.5 mg");
+        assert_eq!(Quantity::new(Rational64::new(1, 3), Unit::Gram).to_string(), "0.33 g");
+        assert_eq!(Quantity::new(Rational64::new(1, 10), Unit::Percent).to_string(), "0.1%");
+    }
+}
@@ -1,24 +1,46 @@
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 
 use num_rational::Rational64;
+use num_traits::{CheckedAdd, CheckedDiv, Zero};
 
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) enum ParseDecimalError {
-    TooManyDots(usize),
+    Empty,
+    TooManySeparators(usize),
+    TooManySlashes(usize),
+    UnexpectedSign,
     MantissaParsing(ParseIntError),
+    WholePartParsing(ParseIntError),
     DenominatorTooLarge,
+    ZeroDenominator,
+    Overflow,
 }
 impl fmt::Display for ParseDecimalError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::TooManyDots(d)
-                => write!(f, "too many ({}) dots", d),
+            Self::Empty
+                => write!(f, "no number given"),
+            Self::TooManySeparators(s)
+                => write!(f, "too many ({}) decimal separators", s),
+            Self::TooManySlashes(s)
+                => write!(f, "too many ({}) fraction slashes", s),
+            Self::UnexpectedSign
+                => write!(f, "a minus sign is only allowed at the start"),
             Self::MantissaParsing(e)
                 => write!(f, "error parsing mantissa: {}", e),
+            Self::WholePartParsing(e)
+                => write!(f, "error parsing whole part of mixed number: {}", e),
             Self::DenominatorTooLarge
                 => write!(f, "denominator too large"),
+            Self::ZeroDenominator
+                => write!(f, "division by zero"),
+            Self::Overflow
+                => write!(f, "number too large"),
         }
     }
 }
@@ -26,7 +48,95 @@ impl std::error::Error for ParseDecimalError {
 }
 
 
-pub(crate) fn parse_decimal(mut text: &str) -> Result<Rational64, ParseDecimalError> {
+/// Parses an amount as entered by a user.
+///
+/// Accepted are decimal numbers with a dot or a comma as the decimal separator (`1.5`, `1,5`),
+/// fractions (`3/2`), mixed numbers (`1 1/2`) and whole numbers followed by a fraction character
+/// (`1½`), each optionally preceded by a minus sign.
+pub(crate) fn parse_amount(text: &str) -> Result<Rational64, ParseDecimalError> {
+    let mut text = text.trim();
+    let mut negate = false;
+    if let Some(rest) = text.strip_prefix('-') {
+        negate = true;
+        text = rest.trim_start();
+    }
+    if text.is_empty() {
+        return Err(ParseDecimalError::Empty);
+    }
+
+    let vulgar_fraction = text.chars()
+        .next_back()
+        .and_then(|c| vulgar_fraction_value(c).map(|v| (c, v)));
+    let value = if let Some((fraction_char, fraction)) = vulgar_fraction {
+        let whole_text = text[..text.len() - fraction_char.len_utf8()].trim_end();
+        let whole = parse_whole_part(whole_text)?;
+        whole.checked_add(&fraction)
+            .ok_or(ParseDecimalError::Overflow)?
+    } else if let Some((before_slash, denom_text)) = text.split_once('/') {
+        let slash_count = text.matches('/').count();
+        if slash_count > 1 {
+            return Err(ParseDecimalError::TooManySlashes(slash_count));
+        }
+        let (whole_text, numer_text) = before_slash.trim_end()
+            .rsplit_once(char::is_whitespace)
+            .unwrap_or(("", before_slash));
+        let numer = parse_unsigned_decimal(numer_text.trim())?;
+        let denom = parse_unsigned_decimal(denom_text.trim())?;
+        if denom.is_zero() {
+            return Err(ParseDecimalError::ZeroDenominator);
+        }
+        let fraction = numer.checked_div(&denom)
+            .ok_or(ParseDecimalError::Overflow)?;
+        parse_whole_part(whole_text.trim())?
+            .checked_add(&fraction)
+            .ok_or(ParseDecimalError::Overflow)?
+    } else {
+        parse_unsigned_decimal(text)?
+    };
+
+    Ok(if negate { -value } else { value })
+}
+
+/// The value of a Unicode vulgar fraction character such as `½`.
+fn vulgar_fraction_value(c: char) -> Option<Rational64> {
+    let (numer, denom) = match c {
+        '\u{BD}' => (1, 2),
+        '\u{BC}' => (1, 4),
+        '\u{BE}' => (3, 4),
+        '\u{2153}' => (1, 3),
+        '\u{2154}' => (2, 3),
+        '\u{215B}' => (1, 8),
+        _ => return None,
+    };
+    Some(Rational64::new(numer, denom))
+}
+
+/// Parses the whole part of a mixed number, which may be absent.
+fn parse_whole_part(text: &str) -> Result<Rational64, ParseDecimalError> {
+    if text.is_empty() {
+        return Ok(Zero::zero());
+    }
+    if text.starts_with('-') {
+        return Err(ParseDecimalError::UnexpectedSign);
+    }
+    let whole: i64 = text.parse()
+        .map_err(ParseDecimalError::WholePartParsing)?;
+    Ok(Rational64::from_integer(whole))
+}
+
+/// Parses a decimal number without a sign, accepting a dot or a comma as the decimal separator.
+fn parse_unsigned_decimal(text: &str) -> Result<Rational64, ParseDecimalError> {
+    if text.starts_with('-') {
+        return Err(ParseDecimalError::UnexpectedSign);
+    }
+    if text.is_empty() {
+        return Err(ParseDecimalError::Empty);
+    }
+    parse_decimal(&text.replace(',', "."))
+}
+
+#[allow(clippy::redundant_closure)]
+fn parse_decimal(mut text: &str) -> Result<Rational64, ParseDecimalError> {
     let mut negate = false;
     if text.starts_with("-") {
         negate = true;
@@ -38,7 +148,7 @@ pub(crate) fn parse_decimal(mut text: &str) -> Result<Rational64, ParseDecimalEr
         .filter(|c| *c == '.')
         .count();
     if dot_count > 1 {
-        return Err(ParseDecimalError::TooManyDots(dot_count));
+        return Err(ParseDecimalError::TooManySeparators(dot_count));
     }
 
     // find position of dot
@@ -69,6 +179,54 @@ pub(crate) fn parse_decimal(mut text: &str) -> Result<Rational64, ParseDecimalEr
 }
 
 
+/// Replaces the file at `path` with the content written by `write` such that a crash halfway
+/// through leaves either the old or the new content in place, never a truncated file.
+///
+/// The content is written into a temporary file next to the target, flushed to disk and then
+/// renamed over the target.
+pub(crate) fn write_file_atomically<F>(path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let mut temp_path_os = path.as_os_str().to_owned();
+    temp_path_os.push(".tmp");
+    let temp_path = PathBuf::from(temp_path_os);
+
+    let result = write_and_rename(path, &temp_path, write);
+    if result.is_err() {
+        // clean up after ourselves; the original error is more interesting than this one
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write_and_rename<F>(path: &Path, temp_path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let temp_file = File::create(temp_path)?;
+    if let Ok(meta) = fs::metadata(path) {
+        // keep the permissions of the file we are replacing
+        temp_file.set_permissions(meta.permissions())?;
+    }
+
+    let mut writer = BufWriter::new(temp_file);
+    write(&mut writer)?;
+    writer.flush()?;
+    let temp_file = writer.into_inner()
+        .map_err(|e| e.into_error())?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    fs::rename(temp_path, path)?;
+
+    // make sure the rename itself is persisted too
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+
 #[cfg(test)]
 mod tests {
     fn test_parse_decimal(expnum: i64, expden: i64, text: &str) {
@@ -121,4 +279,60 @@ mod tests {
         test_parse_decimal(-32, 25, "-1.28");
         test_parse_decimal(-64, 5, "-12.8");
     }
+
+    fn test_parse_amount(expnum: i64, expden: i64, text: &str) {
+        let rat = super::parse_amount(text)
+            .unwrap();
+        assert_eq!(expnum, *rat.numer());
+        assert_eq!(expden, *rat.denom());
+    }
+
+    #[test]
+    fn test_parse_amount_fractions() {
+        test_parse_amount(1, 2, "1/2");
+        test_parse_amount(3, 2, " 1 1/2 ");
+        test_parse_amount(-3, 2, "-1 1/2");
+        test_parse_amount(1, 2, "0,5");
+        test_parse_amount(5, 4, "1,25");
+        test_parse_amount(1, 2, "\u{BD}");
+        test_parse_amount(9, 4, "2\u{BC}");
+        test_parse_amount(7, 4, "1 \u{BE}");
+        test_parse_amount(1, 3, "0.5/1.5");
+        test_parse_amount(12, 1, "12");
+    }
+
+    #[test]
+    fn test_parse_amount_errors() {
+        use super::{parse_amount, ParseDecimalError};
+
+        assert_eq!(parse_amount(" "), Err(ParseDecimalError::Empty));
+        assert_eq!(parse_amount("1.000,5"), Err(ParseDecimalError::TooManySeparators(2)));
+        assert_eq!(parse_amount("1/2/3"), Err(ParseDecimalError::TooManySlashes(2)));
+        assert_eq!(parse_amount("1/0"), Err(ParseDecimalError::ZeroDenominator));
+        assert_eq!(parse_amount("1 -1/2"), Err(ParseDecimalError::UnexpectedSign));
+        assert_eq!(parse_amount("1/"), Err(ParseDecimalError::Empty));
+        assert!(matches!(parse_amount("1.5 1/2"), Err(ParseDecimalError::WholePartParsing(_))));
+        assert!(matches!(parse_amount("abc"), Err(ParseDecimalError::MantissaParsing(_))));
+    }
+
+    #[test]
+    fn test_write_file_atomically() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir()
+            .join(format!("pillreserves-atomic-{}.txt", std::process::id()));
+        super::write_file_atomically(&path, |w| w.write_all(b"first")).unwrap();
+        super::write_file_atomically(&path, |w| w.write_all(b"second")).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+        // a failing writer leaves the previous content untouched
+        let result = super::write_file_atomically(&path, |w| {
+            w.write_all(b"third")?;
+            Err(std::io::Error::other("oops"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }